@@ -0,0 +1,186 @@
+use crate::configuration::Configuration;
+use crate::paths::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a history record is about.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Activate,
+    Deactivate,
+    Create,
+    Delete,
+    Restore,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Activate => write!(f, "activate"),
+            Action::Deactivate => write!(f, "deactivate"),
+            Action::Create => write!(f, "create"),
+            Action::Delete => write!(f, "delete"),
+            Action::Restore => write!(f, "restore"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "ok"),
+            Outcome::Failure => write!(f, "failed"),
+        }
+    }
+}
+
+/// One line of `AUDIT_LOG_PATH`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    /// RFC 3339 in local time
+    pub timestamp: String,
+    pub user: String,
+    pub action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration: Option<String>,
+    /// Tells versions of a configuration apart, see `config_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_hash: Option<String>,
+    pub result: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    pub fn new(user: &str, action: Action) -> Record {
+        Record {
+            timestamp: now(),
+            user: user.to_string(),
+            action,
+            configuration: None,
+            config_hash: None,
+            result: Outcome::Success,
+            error: None,
+        }
+    }
+
+    pub fn name(mut self, name: Option<&str>) -> Record {
+        self.configuration = name.map(str::to_string);
+        self
+    }
+
+    pub fn configuration(mut self, config: &Configuration) -> Record {
+        self.configuration = Some(config.name.clone());
+        self.config_hash = Some(config_hash(config));
+        self
+    }
+
+    pub fn outcome<T, E: fmt::Display>(mut self, result: &Result<T, E>) -> Record {
+        if let Err(e) = result {
+            self.result = Outcome::Failure;
+            self.error = Some(e.to_string());
+        }
+        self
+    }
+
+    /// Appends the record to the log, readable only by its owner.
+    pub fn append(&self) -> io::Result<()> {
+        let mut line = serde_json::to_string(self)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(&*AUDIT_LOG_PATH)?;
+        // a single write, so records of concurrent writers don't interleave
+        file.write_all(line.as_bytes())
+    }
+}
+
+/// The records in the order they were written. Lines that aren't records, e.g. one cut short by
+/// a crash, are skipped.
+pub fn read() -> io::Result<Vec<Record>> {
+    match std::fs::read_to_string(&*AUDIT_LOG_PATH) {
+        Ok(contents) => Ok(contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// FNV-1a of the configuration as it is saved, to see which version was applied. It is not
+/// meant to detect tampering.
+pub fn config_hash(config: &Configuration) -> String {
+    let json = serde_json::to_vec(config).unwrap_or_default();
+    let hash = json.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+/// Name of the account with `uid`, the number if it has none.
+pub fn user_name(uid: u32) -> String {
+    unsafe {
+        let mut passwd: libc::passwd = std::mem::zeroed();
+        let mut buffer = vec![0 as libc::c_char; 4096];
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result);
+
+        if result.is_null() {
+            uid.to_string()
+        } else {
+            CStr::from_ptr(passwd.pw_name).to_string_lossy().into_owned()
+        }
+    }
+}
+
+pub fn current_user() -> String {
+    user_name(unsafe { libc::getuid() })
+}
+
+fn now() -> String {
+    local_timestamp(SystemTime::now())
+}
+
+/// `time` as RFC 3339 in local time.
+pub fn local_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+
+    unsafe {
+        let mut local: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&seconds, &mut local);
+
+        let offset = local.tm_gmtoff / 60;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+            local.tm_year + 1900,
+            local.tm_mon + 1,
+            local.tm_mday,
+            local.tm_hour,
+            local.tm_min,
+            local.tm_sec,
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        )
+    }
+}
+
+/// `timestamp` without the offset, e.g. "2025-03-01 09:00:12".
+pub fn format_timestamp(timestamp: &str) -> String {
+    timestamp.get(..19).unwrap_or(timestamp).replacen('T', " ", 1)
+}
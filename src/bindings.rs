@@ -1,54 +1,133 @@
-use crate::configuration::IptablesRule;
+use crate::configuration::{IptablesRule, RuleAction};
+use crate::helper;
+use crate::nftables;
+use crate::privileges::{self, Backend, CAP_NET_ADMIN};
+use std::fmt;
+use std::path::Path;
 use std::process::Command;
-use crate::paths::*;
 
 
-pub async fn start_redsocks(name: &str) {
+// root (e.g. the helper) and processes with CAP_NET_ADMIN run the tools directly, everyone else
+// goes through sudo; with the capability only killall is left, for a redsocks we started ourselves
+fn privileged(program: &str) -> Command {
+    if privileges::is_root() || privileges::has_capability(CAP_NET_ADMIN) {
+        Command::new(program)
+    } else {
+        let mut command = Command::new("sudo");
+        command.arg(program);
+        command
+    }
+}
+
+/// Starts redsocks with `config_path`, it only returns once redsocks daemonized or failed.
+pub async fn start_redsocks(config_path: &Path) -> anyhow::Result<()> {
     kill_redsocks().await;
-    let _ = Command::new("redsocks")
-        .args(["-c", &format!("{}/{}.conf", &*REDSOCKS_DIR, name)])
-        .output();
+    let output = Command::new("redsocks")
+        .arg("-c")
+        .arg(config_path)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to execute redsocks: {}", e))?;
+
+    if !output.status.success() {
+        anyhow::bail!("redsocks didn't start: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(())
 }
 
+/// An activation whose rules failed after its redsocks started, so that redsocks is running.
+#[derive(Debug)]
+pub struct RulesError(pub anyhow::Error);
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for RulesError {}
+
 pub async fn kill_redsocks() {
-    let _ = Command::new("sudo")
-        .args(["killall", "redsocks"])
+    let _ = privileged("killall")
+        .arg("redsocks")
         .output();
 }
 
-pub async fn flush_iptables() {
-    let _ = Command::new("sudo")
-        .args(["iptables", "-t", "nat", "-F"])
+pub async fn flush_rules() {
+    if privileges::has_capability(CAP_NET_ADMIN) {
+        let _ = nftables::delete_rules();
+        return;
+    }
+
+    let _ = privileged("iptables")
+        .args(["-t", "nat", "-F"])
         .output();
 }
 
+/// Sets up `rules` in order, through nftables when the process may talk to netfilter itself.
+pub async fn apply_rules(rules: &[IptablesRule]) -> anyhow::Result<()> {
+    if privileges::has_capability(CAP_NET_ADMIN) {
+        return nftables::replace_rules(rules)
+            .map_err(|e| anyhow::anyhow!("Failed to set up the nftables rules: {}", e));
+    }
+
+    for rule in rules.iter() {
+        make_iptables_rule(rule).await?;
+    }
+
+    Ok(())
+}
+
 pub async fn deactivate_proxy() {
+    if privileges::backend().await == Backend::Helper {
+        let _ = helper::deactivate().await;
+        return;
+    }
+
     kill_redsocks().await;
-    flush_iptables().await;
-}
-
-pub async fn make_iptables_rule(rule: &IptablesRule) -> anyhow::Result<()> {
-    let status = Command::new("sudo")
-        .args([
-            "iptables",
-            "-t",
-            "nat",
-            "-A",
-            "OUTPUT",
-            "-p",
-            "tcp",
-            "--dport",
-            &rule.dport.to_string(),
-            "-j",
-            &rule.action,
-            "--to-port",
-            &rule.to_port.to_string()
-        ])
+    flush_rules().await;
+}
+
+async fn make_iptables_rule(rule: &IptablesRule) -> anyhow::Result<()> {
+    let mut args = vec![
+        "-t".to_string(),
+        "nat".to_string(),
+        "-A".to_string(),
+        "OUTPUT".to_string(),
+        "-p".to_string(),
+        "tcp".to_string(),
+    ];
+
+    if let Some(destination) = &rule.destination {
+        args.extend(["-d".to_string(), destination.to_string()]);
+    }
+
+    // --dport takes a single port or range, lists need the multiport match
+    if rule.dport.ranges().len() > 1 {
+        args.extend(["-m".to_string(), "multiport".to_string(), "--dports".to_string()]);
+    } else {
+        args.push("--dport".to_string());
+    }
+    args.push(rule.dport.to_string());
+    args.extend(["-j".to_string(), rule.action.to_string()]);
+
+    // only REDIRECT takes a destination port, RETURN rejects it
+    if rule.action == RuleAction::Redirect {
+        args.push("--to-port".to_string());
+        args.push(rule.to_port.to_string());
+    }
+
+    let status = privileged("iptables")
+        .args(&args)
         .output()
         .map_err(|e| anyhow::anyhow!("Failed to execute iptables command: {}", e))?;
 
     if !status.status.success() {
-        anyhow::bail!("Couldn't make an iptables rule");
+        anyhow::bail!(
+            "Couldn't make an iptables rule (iptables {}): {}",
+            args.join(" "),
+            String::from_utf8_lossy(&status.stderr).trim()
+        );
     }
 
     Ok(())
@@ -0,0 +1,303 @@
+use crate::configuration::{
+    Configuration, IptablesRule, Ipv4Net, Ports, Proxy, ProxyKind, RuleAction, BASE_LOCAL_PORT,
+};
+use crate::validation;
+use serde::Deserialize;
+use serde_json::Map;
+use std::net::Ipv4Addr;
+
+// local services stay reachable whatever the catch-all rules redirect
+const LOOPBACK: Ipv4Net = Ipv4Net {
+    addr: Ipv4Addr::new(127, 0, 0, 0),
+    prefix: 8,
+};
+
+#[derive(Deserialize)]
+struct ClashConfig {
+    #[serde(default)]
+    proxies: Vec<ClashProxy>,
+    #[serde(default)]
+    rules: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ClashProxy {
+    name: String,
+    #[serde(rename = "type")]
+    proxy_type: String,
+    #[serde(default)]
+    server: String,
+    #[serde(default)]
+    port: Option<serde_yaml::Value>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    tls: bool,
+}
+
+/// The result of mapping a Clash profile, along with everything that couldn't be carried over.
+pub struct ClashImport {
+    pub configuration: Configuration,
+    pub skipped: Vec<String>,
+}
+
+/// Maps the socks5/http proxies and the port and IP-CIDR rules of a Clash or Clash.Meta profile
+/// to a single configuration, each proxy listening on its own local port.
+pub fn import_clash(contents: &str, name: String) -> anyhow::Result<ClashImport> {
+    let clash: ClashConfig = serde_yaml::from_str(contents)?;
+    let mut skipped = Vec::new();
+    let mut proxies = Vec::new();
+    let mut proxy_names = Vec::new();
+
+    for proxy in clash.proxies.into_iter() {
+        match convert_proxy(&proxy) {
+            Ok(converted) => {
+                proxy_names.push(proxy.name);
+                proxies.push(converted);
+            }
+            Err(reason) => skipped.push(format!("proxy \"{}\": {}", proxy.name, reason)),
+        }
+    }
+
+    // keep loopback and redsocks' own connections to the proxies out of the catch-all rules
+    let mut rules: Vec<IptablesRule> = std::iter::once(LOOPBACK)
+        .chain(proxies.iter().filter_map(|proxy| proxy.url.parse::<Ipv4Net>().ok()))
+        .map(|destination| IptablesRule {
+            dport: Ports::all(),
+            destination: Some(destination),
+            to_port: BASE_LOCAL_PORT,
+            action: RuleAction::Return,
+            extra: Map::new(),
+        })
+        .collect();
+
+    // their own connections can't be kept out, the address isn't known before redsocks resolves it
+    let by_host_name: Vec<(&str, u16)> = proxy_names
+        .iter()
+        .zip(proxies.iter())
+        .filter(|(_, proxy)| proxy.url.parse::<Ipv4Net>().is_err())
+        .map(|(name, proxy)| (name.as_str(), proxy.port))
+        .collect();
+
+    for rule in clash.rules.iter() {
+        match convert_rule(rule, &proxy_names, &by_host_name) {
+            Ok(Some(converted)) => rules.push(converted),
+            Ok(None) => {}
+            Err(reason) => skipped.push(format!("rule \"{}\": {}", rule, reason)),
+        }
+    }
+
+    Ok(ClashImport {
+        configuration: Configuration::new(name, proxies, rules),
+        skipped,
+    })
+}
+
+fn convert_proxy(proxy: &ClashProxy) -> Result<Proxy, String> {
+    let proxy_type = match proxy.proxy_type.as_str() {
+        "socks5" => ProxyKind::Socks5,
+        "http" => ProxyKind::HttpConnect,
+        other => return Err(format!("type \"{}\" isn't supported by redsocks", other)),
+    };
+
+    if proxy.tls {
+        return Err("TLS to the proxy isn't supported by redsocks".to_string());
+    }
+
+    let port = match &proxy.port {
+        Some(serde_yaml::Value::Number(port)) => port.to_string(),
+        Some(serde_yaml::Value::String(port)) => port.clone(),
+        _ => return Err("missing port".to_string()),
+    };
+
+    let converted = Proxy {
+        proxy_type,
+        url: proxy.server.clone(),
+        port: validation::parse_port(&port).map_err(|e| e.to_string())?,
+        login: proxy.username.clone().unwrap_or_default(),
+        password: proxy.password.clone().unwrap_or_default(),
+        extra: Map::new(),
+    };
+    converted.validate().map_err(|e| e.to_string())?;
+
+    Ok(converted)
+}
+
+// Ok(None) means the rule needs nothing from iptables, e.g. a final MATCH,DIRECT. `by_host_name`
+// are the names and ports of the proxies whose server isn't an IP address.
+fn convert_rule(
+    rule: &str,
+    proxy_names: &[String],
+    by_host_name: &[(&str, u16)],
+) -> Result<Option<IptablesRule>, String> {
+    let fields: Vec<&str> = rule.split(',').map(str::trim).collect();
+    let (kind, value, target) = match fields.as_slice() {
+        ["MATCH", target, ..] => ("MATCH", "", *target),
+        [kind, value, target, ..] => (*kind, *value, *target),
+        _ => return Err("expected TYPE,VALUE,TARGET".to_string()),
+    };
+
+    let (to_port, action) = match target {
+        "DIRECT" => (BASE_LOCAL_PORT, RuleAction::Return),
+        "REJECT" | "REJECT-DROP" => return Err("REJECT targets aren't supported".to_string()),
+        name => match proxy_names.iter().position(|proxy_name| proxy_name == name) {
+            Some(index) => (BASE_LOCAL_PORT + index as u16, RuleAction::Redirect),
+            None => return Err(format!("\"{}\" isn't an imported proxy (proxy groups aren't supported)", name)),
+        },
+    };
+
+    let (dport, destination) = match kind {
+        // Clash.Meta writes ranges as 8000-8100 and lists as 80/443
+        "DST-PORT" => {
+            let ports = value.replace('-', ":").replace('/', ",");
+            (ports.parse::<Ports>().map_err(|e| e.to_string())?, None)
+        }
+        "IP-CIDR" => (Ports::all(), Some(value.parse::<Ipv4Net>().map_err(|e| e.to_string())?)),
+        // traffic that no rule redirects already goes out directly
+        "MATCH" if action == RuleAction::Return => return Ok(None),
+        "MATCH" => (Ports::all(), None),
+        "DOMAIN" | "DOMAIN-SUFFIX" | "DOMAIN-KEYWORD" | "GEOSITE" => {
+            return Err("iptables can't match domain names".to_string())
+        }
+        other => return Err(format!("rule type \"{}\" isn't supported", other)),
+    };
+
+    // the proxy's address isn't known to iptables, so any rule covering its port may catch it
+    let caught = by_host_name
+        .iter()
+        .find(|(_, port)| dport.ranges().iter().any(|range| range.start <= *port && *port <= range.end));
+    if let (RuleAction::Redirect, Some((name, port))) = (action, caught) {
+        return Err(format!(
+            "it would redirect redsocks' own connections to proxy \"{}\" (port {}) too, its server has to be an IP address",
+            name, port
+        ));
+    }
+
+    Ok(Some(IptablesRule {
+        dport,
+        destination,
+        to_port,
+        action,
+        extra: Map::new(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(import: &ClashImport) -> Vec<String> {
+        import
+            .configuration
+            .rules
+            .iter()
+            .map(|rule| {
+                let destination = rule.destination.map(|net| format!(" -d {}", net)).unwrap_or_default();
+                format!("{}{} --dport {} -> {}", rule.action, destination, rule.dport, rule.to_port)
+            })
+            .collect()
+    }
+
+    const PROFILE: &str = r#"
+proxies:
+  - { name: a, type: socks5, server: 10.0.0.1, port: 1080, username: u, password: p }
+  - { name: b, type: http, server: 10.0.0.2, port: "3128" }
+  - { name: c, type: vmess, server: 10.0.0.3, port: 443 }
+  - { name: d, type: http, server: 10.0.0.4, port: 443, tls: true }
+rules:
+  - DST-PORT,80/443,a
+  - DST-PORT,8000-8100,b
+  - IP-CIDR,192.168.0.0/16,DIRECT
+  - DOMAIN-SUFFIX,example.com,a
+  - DST-PORT,22,REJECT
+  - DST-PORT,25,group
+  - MATCH,a
+"#;
+
+    #[test]
+    fn imports_proxies_and_rules() {
+        let import = import_clash(PROFILE, "clash".to_string()).unwrap();
+        let proxies: Vec<(ProxyKind, &str, u16, &str)> = import
+            .configuration
+            .proxies
+            .iter()
+            .map(|proxy| (proxy.proxy_type, proxy.url.as_str(), proxy.port, proxy.login.as_str()))
+            .collect();
+        assert_eq!(
+            proxies,
+            [(ProxyKind::Socks5, "10.0.0.1", 1080, "u"), (ProxyKind::HttpConnect, "10.0.0.2", 3128, "")]
+        );
+
+        assert_eq!(
+            rules(&import),
+            [
+                "RETURN -d 127.0.0.0/8 --dport 1:65535 -> 14888",
+                "RETURN -d 10.0.0.1 --dport 1:65535 -> 14888",
+                "RETURN -d 10.0.0.2 --dport 1:65535 -> 14888",
+                "REDIRECT --dport 80,443 -> 14888",
+                "REDIRECT --dport 8000:8100 -> 14889",
+                "RETURN -d 192.168.0.0/16 --dport 1:65535 -> 14888",
+                "REDIRECT --dport 1:65535 -> 14888",
+            ]
+        );
+        assert!(import.configuration.validate(std::iter::empty()).is_ok());
+
+        let skipped: Vec<&str> = import.skipped.iter().map(|reason| reason.split(':').next().unwrap()).collect();
+        assert_eq!(
+            skipped,
+            [
+                "proxy \"c\"",
+                "proxy \"d\"",
+                "rule \"DOMAIN-SUFFIX,example.com,a\"",
+                "rule \"DST-PORT,22,REJECT\"",
+                "rule \"DST-PORT,25,group\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn match_direct_needs_no_rule() {
+        let profile = "proxies:\n  - { name: a, type: socks5, server: 10.0.0.1, port: 1080 }\nrules:\n  - MATCH,DIRECT\n";
+        let import = import_clash(profile, "clash".to_string()).unwrap();
+        assert_eq!(rules(&import).len(), 2);
+        assert!(import.skipped.is_empty());
+    }
+
+    #[test]
+    fn rules_covering_a_proxy_given_by_host_name_are_skipped() {
+        let profile = r#"
+proxies:
+  - { name: a, type: socks5, server: proxy.example.com, port: 1080 }
+  - { name: b, type: socks5, server: 10.0.0.2, port: 1081 }
+rules:
+  - DST-PORT,443,a
+  - DST-PORT,1000-2000,b
+  - DST-PORT,1080,DIRECT
+  - IP-CIDR,10.1.0.0/16,a
+  - MATCH,b
+"#;
+        let import = import_clash(profile, "clash".to_string()).unwrap();
+        assert_eq!(
+            rules(&import),
+            [
+                "RETURN -d 127.0.0.0/8 --dport 1:65535 -> 14888",
+                "RETURN -d 10.0.0.2 --dport 1:65535 -> 14888",
+                "REDIRECT --dport 443 -> 14888",
+                "RETURN --dport 1080 -> 14888",
+            ]
+        );
+        assert_eq!(import.skipped.len(), 3);
+        assert!(import.skipped.iter().all(|reason| reason.contains("proxy \"a\" (port 1080)")));
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        assert!(import_clash("proxies: [", "clash".to_string()).is_err());
+
+        let import = import_clash("rules:\n  - DST-PORT,80\n  - DST-PORT,x,DIRECT\n", "clash".to_string()).unwrap();
+        assert!(import.configuration.proxies.is_empty());
+        assert_eq!(import.skipped.len(), 2);
+    }
+}
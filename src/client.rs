@@ -0,0 +1,128 @@
+use crate::paths::*;
+use crate::rpc::{ConfigurationSummary, Event, Message, Request, Statistics, Status};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+/// A connection to proxswapd.
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+    // events that arrived while waiting for a response
+    pending_events: VecDeque<Event>,
+}
+
+impl Client {
+    pub async fn connect() -> anyhow::Result<Client> {
+        Self::connect_to(&SOCKET_PATH).await.map_err(|e| {
+            anyhow::anyhow!(
+                "proxswapd isn't running ({}: {}), start it with `proxswap daemon`",
+                *SOCKET_PATH,
+                e
+            )
+        })
+    }
+
+    /// Connects to another JSON-RPC socket, e.g. the privileged helper's.
+    pub async fn connect_to(path: &str) -> std::io::Result<Client> {
+        let stream = UnixStream::connect(path).await?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(Client {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+            pending_events: VecDeque::new(),
+        })
+    }
+
+    pub async fn list(&mut self) -> anyhow::Result<Vec<ConfigurationSummary>> {
+        Ok(serde_json::from_value(self.call("list", Value::Null).await?)?)
+    }
+
+    pub async fn activate(&mut self, name: &str) -> anyhow::Result<()> {
+        self.call("activate", json!({ "name": name })).await?;
+        Ok(())
+    }
+
+    pub async fn deactivate(&mut self) -> anyhow::Result<()> {
+        self.call("deactivate", Value::Null).await?;
+        Ok(())
+    }
+
+    pub async fn status(&mut self) -> anyhow::Result<Status> {
+        Ok(serde_json::from_value(self.call("status", Value::Null).await?)?)
+    }
+
+    pub async fn stats(&mut self) -> anyhow::Result<Statistics> {
+        Ok(serde_json::from_value(self.call("stats", Value::Null).await?)?)
+    }
+
+    /// Asks the daemon to send events on this connection, read them with `next_event`.
+    pub async fn subscribe(&mut self) -> anyhow::Result<()> {
+        self.call("subscribe", Value::Null).await?;
+        Ok(())
+    }
+
+    pub async fn next_event(&mut self) -> anyhow::Result<Event> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(event);
+        }
+
+        loop {
+            if let Some(event) = into_event(self.read_message().await?) {
+                return Ok(event);
+            }
+        }
+    }
+
+    pub async fn call(&mut self, method: &str, params: Value) -> anyhow::Result<Value> {
+        self.next_id += 1;
+        let id = json!(self.next_id);
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            id: Some(id.clone()),
+            method: method.to_string(),
+            params,
+        };
+
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+
+        loop {
+            let message = self.read_message().await?;
+
+            if message.id.as_ref() == Some(&id) {
+                if let Some(error) = message.error {
+                    return Err(error.into());
+                }
+                return Ok(message.result.unwrap_or(Value::Null));
+            }
+
+            if let Some(event) = into_event(message) {
+                self.pending_events.push_back(event);
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> anyhow::Result<Message> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("proxswapd closed the connection"))?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+fn into_event(message: Message) -> Option<Event> {
+    match message.method.as_deref() {
+        Some("event") => serde_json::from_value(message.params?).ok(),
+        _ => None,
+    }
+}
@@ -1,102 +1,437 @@
-use crate::bindings::{make_iptables_rule, start_redsocks};
-use anyhow::Ok;
+use crate::bindings::{apply_rules, start_redsocks, RulesError};
+use crate::helper;
+use crate::privileges::{self, Backend};
 use serde::{Deserialize, Serialize};
-use std::fs::{File, remove_file};
+use std::fmt;
+use serde_json::{Map, Value};
+use std::fs::{create_dir_all, rename, File, remove_file};
 use std::io::prelude::*;
-use std::path::Path;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use crate::migration::SCHEMA_VERSION;
 use crate::paths::*;
+use crate::schedule::Schedule;
+use crate::template::TemplateRef;
+use crate::trash;
+use crate::validation::{self, ValidationError};
 
+/// Local port of the first proxy in the redsocks chain; each following proxy gets the next one.
+pub const BASE_LOCAL_PORT: u16 = 14888;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Proxy protocols redsocks can speak, serialized under their redsocks `type` names.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProxyKind {
+    Socks4,
+    Socks5,
+    HttpConnect,
+    HttpRelay,
+}
+
+impl ProxyKind {
+    pub const ALL: [ProxyKind; 4] = [
+        ProxyKind::Socks4,
+        ProxyKind::Socks5,
+        ProxyKind::HttpConnect,
+        ProxyKind::HttpRelay,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyKind::Socks4 => "socks4",
+            ProxyKind::Socks5 => "socks5",
+            ProxyKind::HttpConnect => "http-connect",
+            ProxyKind::HttpRelay => "http-relay",
+        }
+    }
+}
+
+impl fmt::Display for ProxyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ProxyKind {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "socks4" => Ok(ProxyKind::Socks4),
+            "socks5" => Ok(ProxyKind::Socks5),
+            // plain "http" is what older configs used for a CONNECT proxy
+            "http-connect" | "http" => Ok(ProxyKind::HttpConnect),
+            "http-relay" => Ok(ProxyKind::HttpRelay),
+            _ => Err(ValidationError::UnsupportedProxyType(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for ProxyKind {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ProxyKind> for String {
+    fn from(kind: ProxyKind) -> Self {
+        kind.as_str().to_string()
+    }
+}
+
+/// A single port or an inclusive `start:end` range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}:{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValidationError::InvalidDport(s.to_string());
+        let (start, end) = s.trim().split_once(':').unwrap_or((s.trim(), s.trim()));
+        let start = validation::parse_port(start).map_err(|_| invalid())?;
+        let end = validation::parse_port(end).map_err(|_| invalid())?;
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+/// Destination ports a rule matches, written as `80`, `8000:8100` or a comma separated list of both.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "PortsRepr", into = "String")]
+pub struct Ports(Vec<PortRange>);
+
+impl Ports {
+    pub fn all() -> Self {
+        Ports(vec![PortRange { start: 1, end: u16::MAX }])
+    }
+
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self.0.iter().map(|range| range.to_string()).collect();
+        f.write_str(&ranges.join(","))
+    }
+}
+
+impl FromStr for Ports {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|range| range.parse().map_err(|_| ValidationError::InvalidDport(s.to_string())))
+            .collect::<Result<Vec<PortRange>, ValidationError>>()?;
+
+        Ok(Ports(ranges))
+    }
+}
+
+// older configs stored dport either as a string or as a bare number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortsRepr {
+    Number(u16),
+    Text(String),
+}
+
+impl TryFrom<PortsRepr> for Ports {
+    type Error = ValidationError;
+
+    fn try_from(value: PortsRepr) -> Result<Self, Self::Error> {
+        match value {
+            PortsRepr::Number(port) => port.to_string().parse(),
+            PortsRepr::Text(ports) => ports.parse(),
+        }
+    }
+}
+
+impl From<Ports> for String {
+    fn from(ports: Ports) -> Self {
+        ports.to_string()
+    }
+}
+
+/// An IPv4 address or CIDR block a rule is limited to, e.g. `10.0.0.0/8`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ipv4Net {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Ipv4Net {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0))
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask());
+        u32::from(addr) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl fmt::Display for Ipv4Net {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == 32 {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValidationError::InvalidDestination(s.to_string());
+        let (addr, prefix) = s.trim().split_once('/').unwrap_or((s.trim(), "32"));
+        let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+
+        if prefix > 32 {
+            return Err(invalid());
+        }
+
+        Ok(Ipv4Net { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Ipv4Net {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Ipv4Net> for String {
+    fn from(net: Ipv4Net) -> Self {
+        net.to_string()
+    }
+}
+
+/// iptables targets a rule can jump to from the nat OUTPUT chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum RuleAction {
+    Redirect,
+    Return,
+}
+
+impl RuleAction {
+    pub const ALL: [RuleAction; 2] = [RuleAction::Redirect, RuleAction::Return];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleAction::Redirect => "REDIRECT",
+            RuleAction::Return => "RETURN",
+        }
+    }
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RuleAction {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_uppercase().as_str() {
+            "REDIRECT" => Ok(RuleAction::Redirect),
+            "RETURN" => Ok(RuleAction::Return),
+            _ => Err(ValidationError::UnsupportedAction(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for RuleAction {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<RuleAction> for String {
+    fn from(action: RuleAction) -> Self {
+        action.as_str().to_string()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Proxy {
-    pub proxy_type: String,
+    pub proxy_type: ProxyKind,
     pub url: String,
-    pub port: u32,
+    pub port: u16,
+    #[serde(default)]
     pub login: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IptablesRule {
-    pub dport: String,
+    pub dport: Ports,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<Ipv4Net>,
     pub to_port: u16,
-    pub action: String,
+    pub action: RuleAction,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Configuration {
+    pub version: u32,
     pub name: String,
     pub proxies: Vec<Proxy>,
     pub rules: Vec<IptablesRule>,
+    /// When proxswapd activates the configuration on its own
+    #[serde(default, skip_serializing_if = "Schedule::is_empty")]
+    pub schedule: Schedule,
+    /// The template the configuration is built from, see template.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<TemplateRef>,
+    /// Group the configuration is listed under in the TUI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Pinned to the top of the list
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub favourite: bool,
+    // keys this version doesn't know about, written back untouched on save
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl Configuration {
-    pub async fn new(
+    pub fn new(
         config_name: String,
         proxies: Vec<Proxy>,
         rules: Vec<IptablesRule>,
     ) -> Configuration {
-        let config_path: &str = &format!("{}/{}.json", &*CONFIG_DIR, &config_name);
-
-        let conf = if Path::new(&config_path).is_file() {
-            Configuration {
-                name: config_name,
-                proxies: proxies,
-                rules: rules,
-            }
-        } else {
-            let _ = File::create(config_path);
-            Configuration {
-                name: config_name,
-                proxies: proxies,
-                rules: rules,
-            }
-        };
-
-        conf.generate_redsocks_config().await.unwrap();
+        Configuration {
+            version: SCHEMA_VERSION,
+            name: config_name,
+            proxies,
+            rules,
+            schedule: Schedule::default(),
+            template: None,
+            group: None,
+            tags: Vec::new(),
+            favourite: false,
+            extra: Map::new(),
+        }
+    }
 
-        conf.make_configuration_file().await.unwrap();
+    /// A copy saved under `name`. A copy of a configuration made from a template keeps following it.
+    pub fn duplicate(&self, name: &str) -> Configuration {
+        Configuration {
+            name: name.to_string(),
+            ..self.clone()
+        }
+    }
 
-        conf
+    pub fn config_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}/{}.json", &*CONFIG_DIR, &self.name))
     }
 
-    pub async fn run(&self) {
-        start_redsocks(&self.name).await;
+    pub fn redsocks_config_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}/{}.conf", &*REDSOCKS_DIR, &self.name))
+    }
 
-        for rule in self.rules.iter() {
-            let _ = make_iptables_rule(rule).await;
+    pub async fn run(&self) -> anyhow::Result<()> {
+        // a running helper does the privileged part, so nothing has to go through sudo
+        if privileges::backend().await == Backend::Helper {
+            return helper::activate(self).await;
         }
+
+        // redsocks configs are derived data, regenerate them from the current proxies on every activation
+        self.generate_redsocks_config().await?;
+
+        start_redsocks(&self.redsocks_config_path()).await?;
+
+        apply_rules(&self.rules).await.map_err(|e| RulesError(e).into())
     }
 
-    pub fn delete_configuration(&self) -> Result<(), anyhow::Error> {
-        let _ = remove_file(format!("{}/{}.json", &*CONFIG_DIR, &self.name));
-        let _ = remove_file(format!("{}/{}.conf", &*REDSOCKS_DIR, &self.name));
+    /// Writes the configuration file, replacing any previous version atomically.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(&self)?;
 
-        Ok(())
+        write_atomic(&self.config_path(), json.as_bytes())
     }
 
-    async fn make_configuration_file(&self) -> Result<(), anyhow::Error> {
-        let mut file = File::create(format!("{}/{}.json", &*CONFIG_DIR, &self.name)).unwrap();
-        let json = serde_json::to_string(&self).unwrap();
+    /// Like `save`, but refuses to replace a file that's already there, even one that failed to load.
+    pub fn save_new(&self) -> anyhow::Result<()> {
+        if self.config_path().exists() {
+            return Err(ValidationError::DuplicateName(self.name.clone()).into());
+        }
 
-        let _ = file.write_all(json.as_bytes());
+        self.save()
+    }
 
-        Ok(())
+    /// Moves the configuration to the trash, `trash::restore` brings it back.
+    pub fn delete_configuration(&self) -> anyhow::Result<trash::Entry> {
+        trash::move_to_trash(self)
     }
 
-    async fn generate_redsocks_config(&self) -> Result<(), anyhow::Error> {
-        let mut file = File::create(format!("{}/{}.conf", &*REDSOCKS_DIR, &self.name))?;
-        let mut proxy_chain: Vec<String> = vec![r#"base {
+    pub fn redsocks_config(&self) -> String {
+        self.redsocks_config_as(None)
+    }
+
+    /// The redsocks config for a redsocks started as root, which switches to `run_as` (a user and
+    /// maybe a group) once it listens.
+    pub fn redsocks_config_as(&self, run_as: Option<(&str, Option<&str>)>) -> String {
+        let mut base = String::from(
+            r#"base {
     log_debug = off;
     log_info = off;
     daemon = on;
     redirector = iptables;
-}
-"#
-        .to_string()];
-        let mut local_port = 14888; // start port used by the app, the first proxy's local_port in the chain
+"#,
+        );
+        if let Some((user, group)) = run_as {
+            base.push_str(&format!("    user = {};\n", user));
+            if let Some(group) = group {
+                base.push_str(&format!("    group = {};\n", group));
+            }
+        }
+        base.push_str("}\n");
 
-        for proxy in self.proxies.iter() {
+        let mut proxy_chain: Vec<String> = vec![base];
+        for (local_port, proxy) in (BASE_LOCAL_PORT..).zip(self.proxies.iter()) {
             proxy_chain.push(format!(
 r#"redsocks {{
     local_ip = 127.0.0.1;
@@ -105,7 +440,7 @@ r#"redsocks {{
     type = {};
     ip = {};
     port = {};"#, local_port, proxy.proxy_type, proxy.url, proxy.port));
-            if proxy.login != "" && proxy.password != "" {
+            if !proxy.login.is_empty() && !proxy.password.is_empty() {
                 proxy_chain.push(format!(
 r#"
     login = {};
@@ -117,12 +452,143 @@ r#"
 
             proxy_chain.push(r#"}
 "#.to_string());       
-            local_port += 1;
         }
 
+        proxy_chain.join("\n")
+    }
+
+    async fn generate_redsocks_config(&self) -> Result<(), anyhow::Error> {
+        create_dir_all(&*REDSOCKS_DIR)?;
+
+        write_atomic(&self.redsocks_config_path(), self.redsocks_config().as_bytes())
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a half written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    rename(&tmp_path, path).inspect_err(|_| {
+        let _ = remove_file(&tmp_path);
+    })?;
+
+    Ok(())
+}
 
-        let _ = file.write_all(proxy_chain.join("\n").as_bytes());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rule(dport: &str) -> IptablesRule {
+        IptablesRule {
+            dport: dport.parse().unwrap(),
+            destination: None,
+            to_port: BASE_LOCAL_PORT,
+            action: RuleAction::Redirect,
+            extra: Map::new(),
+        }
+    }
+
+    #[test]
+    fn proxy_kinds() {
+        for kind in ProxyKind::ALL {
+            assert_eq!(kind.as_str().parse::<ProxyKind>(), Ok(kind));
+            assert_eq!(serde_json::to_value(kind).unwrap(), json!(kind.as_str()));
+            assert_eq!(serde_json::from_value::<ProxyKind>(json!(kind.as_str())).unwrap(), kind);
+        }
+        assert_eq!(" SOCKS5 ".parse::<ProxyKind>(), Ok(ProxyKind::Socks5));
+        // what older files call a CONNECT proxy, written back under its new name
+        let old: ProxyKind = serde_json::from_value(json!("http")).unwrap();
+        assert_eq!(old, ProxyKind::HttpConnect);
+        assert_eq!(serde_json::to_value(old).unwrap(), json!("http-connect"));
+
+        assert_eq!(
+            "https".parse::<ProxyKind>(),
+            Err(ValidationError::UnsupportedProxyType("https".to_string()))
+        );
+        assert!(serde_json::from_value::<ProxyKind>(json!("socks6")).is_err());
+    }
+
+    #[test]
+    fn ports() {
+        let ports: Ports = "80, 8000:8100,443".parse().unwrap();
+        assert_eq!(
+            ports.ranges(),
+            [
+                PortRange { start: 80, end: 80 },
+                PortRange { start: 8000, end: 8100 },
+                PortRange { start: 443, end: 443 },
+            ]
+        );
+        assert_eq!(ports.to_string(), "80,8000:8100,443");
+        assert_eq!("22:22".parse::<Ports>().unwrap().to_string(), "22");
+        assert_eq!(Ports::all().to_string(), "1:65535");
+
+        for invalid in ["", "0", "65536", "80,", "100:90", "80:", "http", "1-2"] {
+            assert_eq!(invalid.parse::<Ports>(), Err(ValidationError::InvalidDport(invalid.to_string())));
+        }
+    }
+
+    #[test]
+    fn ports_serialization() {
+        let ports: Ports = "80,8000:8100".parse().unwrap();
+        assert_eq!(serde_json::to_value(&ports).unwrap(), json!("80,8000:8100"));
+        assert_eq!(serde_json::from_value::<Ports>(json!("80,8000:8100")).unwrap(), ports);
+        // older files had a bare number
+        assert_eq!(serde_json::from_value::<Ports>(json!(443)).unwrap(), "443".parse().unwrap());
+        assert!(serde_json::from_value::<Ports>(json!(0)).is_err());
+        assert!(serde_json::from_value::<Ports>(json!(["80"])).is_err());
+    }
+
+    #[test]
+    fn rule_actions_and_destinations() {
+        assert_eq!("redirect".parse::<RuleAction>(), Ok(RuleAction::Redirect));
+        assert_eq!(serde_json::to_value(RuleAction::Return).unwrap(), json!("RETURN"));
+        assert!("DROP".parse::<RuleAction>().is_err());
+
+        let net: Ipv4Net = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.1.2.3/8");
+        assert_eq!("10.0.0.1/32".parse::<Ipv4Net>().unwrap().to_string(), "10.0.0.1");
+        for invalid in ["10.0.0.0/33", "10.0.0", "10.0.0.0/", "::1"] {
+            assert!(invalid.parse::<Ipv4Net>().is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn old_rules_still_load() {
+        let rule: IptablesRule =
+            serde_json::from_value(json!({ "dport": 80, "to_port": 14888, "action": "redirect", "comment": "web" }))
+                .unwrap();
+        assert_eq!(rule.dport.to_string(), "80");
+        assert_eq!(rule.action, RuleAction::Redirect);
+        // written back in the current form, unknown keys kept
+        assert_eq!(
+            serde_json::to_value(&rule).unwrap(),
+            json!({ "dport": "80", "to_port": 14888, "action": "REDIRECT", "comment": "web" })
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn multiport_limit() {
+        let fifteen = (1..=15).map(|port| port.to_string()).collect::<Vec<_>>().join(",");
+        assert!(rule(&fifteen).validate(1).is_ok());
+        assert_eq!(
+            rule(&format!("{},16", fifteen)).validate(1),
+            Err(ValidationError::TooManyPorts(format!("{},16", fifteen)))
+        );
+        // a range counts as 2
+        assert!(rule("1:2,3:4,5:6,7:8,9:10,11:12,13").validate(1).is_ok());
+        assert!(rule("1:2,3:4,5:6,7:8,9:10,11:12,13:14,15:16").validate(1).is_err());
+        // one range doesn't need multiport
+        assert!(rule("1:65535").validate(1).is_ok());
     }
 }
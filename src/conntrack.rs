@@ -0,0 +1,499 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+// linux/netlink.h
+const NETLINK_NETFILTER: i32 = 12;
+const SOL_NETLINK: i32 = 270;
+const NETLINK_ADD_MEMBERSHIP: i32 = 1;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 0x2;
+const NLMSG_DONE: u16 = 0x3;
+
+// linux/netfilter/nfnetlink.h and nfnetlink_conntrack.h
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_NEW: u16 = 0;
+const IPCTNL_MSG_CT_GET: u16 = 1;
+const IPCTNL_MSG_CT_DELETE: u16 = 2;
+const NFNLGRP_CONNTRACK_DESTROY: u32 = 3;
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_STATUS: u16 = 3;
+const CTA_PROTOINFO: u16 = 4;
+const CTA_COUNTERS_ORIG: u16 = 9;
+const CTA_COUNTERS_REPLY: u16 = 10;
+const CTA_ID: u16 = 12;
+const CTA_TIMESTAMP: u16 = 20;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_PROTO_NUM: u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+const CTA_COUNTERS_PACKETS: u16 = 1;
+const CTA_COUNTERS_BYTES: u16 = 2;
+const CTA_TIMESTAMP_START: u16 = 1;
+const CTA_PROTOINFO_TCP: u16 = 1;
+const CTA_PROTOINFO_TCP_STATE: u16 = 1;
+const TCP_CONNTRACK_TIME_WAIT: u8 = 7;
+const TCP_CONNTRACK_CLOSE: u8 = 8;
+const IPS_ASSURED: u32 = 1 << 2;
+const IPPROTO_TCP: u8 = 6;
+
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// counters and start times are only tracked once these are on
+const SYSCTLS: [&str; 2] = [
+    "/proc/sys/net/netfilter/nf_conntrack_acct",
+    "/proc/sys/net/netfilter/nf_conntrack_timestamp",
+];
+
+/// A TCP connection the kernel tracks.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub id: u32,
+    /// Where the application connected to
+    pub destination: SocketAddrV4,
+    /// Who answers, redsocks on 127.0.0.1 for redirected connections
+    pub reply_source: SocketAddrV4,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub packets_down: u64,
+    /// Whether traffic was seen in both directions
+    pub assured: bool,
+    /// False once both sides closed, the kernel keeps closed connections around for a while
+    pub open: bool,
+    pub started: Option<SystemTime>,
+}
+
+/// Whether the kernel counts bytes and start times of new connections.
+pub fn accounting_enabled() -> bool {
+    SYSCTLS.iter().all(|path| std::fs::read_to_string(path).is_ok_and(|value| value.trim() == "1"))
+}
+
+/// Turns on byte counters and start times for new connections, for the whole host and until
+/// they are turned off again. False without the privileges.
+pub fn enable_accounting() -> bool {
+    SYSCTLS.iter().all(|path| {
+        std::fs::read_to_string(path).is_ok_and(|value| value.trim() == "1") || std::fs::write(path, "1").is_ok()
+    })
+}
+
+/// Every IPv4 TCP connection in the conntrack table.
+pub async fn dump() -> io::Result<Vec<Connection>> {
+    let socket = register(open_socket()?)?;
+
+    // struct nlmsghdr followed by struct nfgenmsg, no attributes
+    let mut request = Vec::new();
+    request.extend(20u32.to_ne_bytes());
+    request.extend(((NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_GET).to_ne_bytes());
+    request.extend((NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    request.extend(1u32.to_ne_bytes());
+    request.extend(0u32.to_ne_bytes());
+    request.extend([libc::AF_INET as u8, 0, 0, 0]);
+
+    let sent = unsafe { libc::send(socket.as_raw_fd(), request.as_ptr().cast(), request.len(), 0) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut connections = Vec::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        // don't wait forever if the kernel never answers
+        let received = tokio::time::timeout(DUMP_TIMEOUT, receive(&socket, &mut buffer))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the conntrack dump didn't finish"))??;
+
+        for (kind, payload) in messages(&buffer[..received]) {
+            match kind {
+                NLMSG_DONE => return Ok(connections),
+                NLMSG_ERROR => {
+                    let error = i32::from_ne_bytes(payload.get(..4).unwrap_or(&[0; 4]).try_into().unwrap());
+                    if error != 0 {
+                        return Err(io::Error::from_raw_os_error(-error));
+                    }
+                }
+                _ => connections.extend(parse_connection(kind, payload)),
+            }
+        }
+    }
+}
+
+/// Connections as they are removed from the conntrack table, with their final counters.
+pub struct DestroyEvents {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl DestroyEvents {
+    pub fn new() -> io::Result<DestroyEvents> {
+        let socket = open_socket()?;
+        let group = NFNLGRP_CONNTRACK_DESTROY;
+
+        let joined = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                SOL_NETLINK,
+                NETLINK_ADD_MEMBERSHIP,
+                (&group as *const u32).cast(),
+                std::mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if joined < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DestroyEvents {
+            socket: register(socket)?,
+        })
+    }
+
+    pub async fn next(&mut self) -> io::Result<Vec<Connection>> {
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            match receive(&self.socket, &mut buffer).await {
+                Ok(received) => {
+                    return Ok(messages(&buffer[..received])
+                        .filter_map(|(kind, payload)| parse_connection(kind, payload))
+                        .collect());
+                }
+                // events came faster than they were read, the dropped ones are lost
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn register(socket: OwnedFd) -> io::Result<AsyncFd<OwnedFd>> {
+    // SAFETY: the AsyncFd owns the OwnedFd, so the descriptor stays open and the same for as long
+    // as it is registered. Tokio marks the call unsafe because it can't check that for any AsRawFd.
+    Ok(unsafe { AsyncFd::register_with_interest(socket, Interest::READABLE)? })
+}
+
+// waits for the next datagram without blocking the runtime
+async fn receive(socket: &AsyncFd<OwnedFd>, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = socket.readable().await?;
+        let received = guard.try_io(|socket| {
+            let received =
+                unsafe { libc::recv(socket.get_ref().as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+            if received < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(received as usize)
+            }
+        });
+
+        match received {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+fn open_socket() -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            NETLINK_NETFILTER,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+
+        let mut address: libc::sockaddr_nl = std::mem::zeroed();
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        if libc::bind(
+            fd,
+            (&address as *const libc::sockaddr_nl).cast(),
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+// netlink messages in a datagram as (type, payload after the nlmsghdr)
+fn messages(mut buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buffer.len() < 16 {
+            return None;
+        }
+        let len = u32::from_ne_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buffer[4..6].try_into().unwrap());
+        if len < 16 || len > buffer.len() {
+            return None;
+        }
+
+        let payload = &buffer[16..len];
+        buffer = &buffer[len.next_multiple_of(4).min(buffer.len())..];
+        Some((kind, payload))
+    })
+}
+
+// attributes as (type without the nested/byte order flags, value)
+fn attributes(mut buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buffer.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes(buffer[0..2].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buffer[2..4].try_into().unwrap()) & 0x3fff;
+        if len < 4 || len > buffer.len() {
+            return None;
+        }
+
+        let value = &buffer[4..len];
+        buffer = &buffer[len.next_multiple_of(4).min(buffer.len())..];
+        Some((kind, value))
+    })
+}
+
+fn find(buffer: &[u8], kind: u16) -> Option<&[u8]> {
+    attributes(buffer).find(|(found, _)| *found == kind).map(|(_, value)| value)
+}
+
+// integers are in network byte order
+fn be_u64(value: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(value.get(..8)?.try_into().ok()?))
+}
+
+fn be_u32(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+}
+
+fn be_u16(value: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(value.get(..2)?.try_into().ok()?))
+}
+
+// (source, destination) of a CTA_TUPLE_* attribute, None for anything but IPv4 TCP
+fn parse_tuple(tuple: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4)> {
+    let ip = find(tuple, CTA_TUPLE_IP)?;
+    let proto = find(tuple, CTA_TUPLE_PROTO)?;
+    if *find(proto, CTA_PROTO_NUM)?.first()? != IPPROTO_TCP {
+        return None;
+    }
+
+    let source = Ipv4Addr::from(be_u32(find(ip, CTA_IP_V4_SRC)?)?);
+    let destination = Ipv4Addr::from(be_u32(find(ip, CTA_IP_V4_DST)?)?);
+    let source_port = be_u16(find(proto, CTA_PROTO_SRC_PORT)?)?;
+    let destination_port = be_u16(find(proto, CTA_PROTO_DST_PORT)?)?;
+
+    Some((SocketAddrV4::new(source, source_port), SocketAddrV4::new(destination, destination_port)))
+}
+
+fn parse_connection(kind: u16, payload: &[u8]) -> Option<Connection> {
+    if kind >> 8 != NFNL_SUBSYS_CTNETLINK || !matches!(kind & 0xff, IPCTNL_MSG_CT_NEW | IPCTNL_MSG_CT_DELETE) {
+        return None;
+    }
+    // skip struct nfgenmsg
+    let data = payload.get(4..)?;
+
+    let (_, destination) = parse_tuple(find(data, CTA_TUPLE_ORIG)?)?;
+    let (reply_source, _) = parse_tuple(find(data, CTA_TUPLE_REPLY)?)?;
+    let counter = |direction: u16, counter: u16| {
+        find(data, direction).and_then(|counters| find(counters, counter)).and_then(be_u64).unwrap_or_default()
+    };
+
+    Some(Connection {
+        id: find(data, CTA_ID).and_then(be_u32).unwrap_or_default(),
+        destination,
+        reply_source,
+        bytes_up: counter(CTA_COUNTERS_ORIG, CTA_COUNTERS_BYTES),
+        bytes_down: counter(CTA_COUNTERS_REPLY, CTA_COUNTERS_BYTES),
+        packets_down: counter(CTA_COUNTERS_REPLY, CTA_COUNTERS_PACKETS),
+        assured: find(data, CTA_STATUS).and_then(be_u32).is_some_and(|status| status & IPS_ASSURED != 0),
+        open: find(data, CTA_PROTOINFO)
+            .and_then(|info| find(info, CTA_PROTOINFO_TCP))
+            .and_then(|tcp| find(tcp, CTA_PROTOINFO_TCP_STATE))
+            .and_then(|state| state.first())
+            .is_none_or(|&state| state != TCP_CONNTRACK_TIME_WAIT && state != TCP_CONNTRACK_CLOSE),
+        started: find(data, CTA_TIMESTAMP)
+            .and_then(|timestamp| find(timestamp, CTA_TIMESTAMP_START))
+            .and_then(be_u64)
+            .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NLA_F_NESTED: u16 = 0x8000;
+    const CT_NEW: u16 = (NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_NEW;
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attribute = Vec::new();
+        attribute.extend(((value.len() + 4) as u16).to_ne_bytes());
+        attribute.extend(kind.to_ne_bytes());
+        attribute.extend(value);
+        attribute.resize(attribute.len().next_multiple_of(4), 0);
+        attribute
+    }
+
+    fn nested(kind: u16, attributes: &[Vec<u8>]) -> Vec<u8> {
+        attribute(kind | NLA_F_NESTED, &attributes.concat())
+    }
+
+    fn tuple(kind: u16, protocol: u8, source: SocketAddrV4, destination: SocketAddrV4) -> Vec<u8> {
+        nested(
+            kind,
+            &[
+                nested(
+                    CTA_TUPLE_IP,
+                    &[
+                        attribute(CTA_IP_V4_SRC, &source.ip().octets()),
+                        attribute(CTA_IP_V4_DST, &destination.ip().octets()),
+                    ],
+                ),
+                nested(
+                    CTA_TUPLE_PROTO,
+                    &[
+                        attribute(CTA_PROTO_NUM, &[protocol]),
+                        attribute(CTA_PROTO_SRC_PORT, &source.port().to_be_bytes()),
+                        attribute(CTA_PROTO_DST_PORT, &destination.port().to_be_bytes()),
+                    ],
+                ),
+            ],
+        )
+    }
+
+    fn counters(kind: u16, packets: u64, bytes: u64) -> Vec<u8> {
+        nested(
+            kind,
+            &[
+                attribute(CTA_COUNTERS_PACKETS, &packets.to_be_bytes()),
+                attribute(CTA_COUNTERS_BYTES, &bytes.to_be_bytes()),
+            ],
+        )
+    }
+
+    fn tcp_state(state: u8) -> Vec<u8> {
+        nested(CTA_PROTOINFO, &[nested(CTA_PROTOINFO_TCP, &[attribute(CTA_PROTOINFO_TCP_STATE, &[state])])])
+    }
+
+    // struct nfgenmsg followed by the attributes
+    fn payload(attributes: &[Vec<u8>]) -> Vec<u8> {
+        [vec![libc::AF_INET as u8, 0, 0, 0], attributes.concat()].concat()
+    }
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend(((payload.len() + 16) as u32).to_ne_bytes());
+        message.extend(kind.to_ne_bytes());
+        message.extend([0; 10]);
+        message.extend(payload);
+        message
+    }
+
+    fn address(address: &str) -> SocketAddrV4 {
+        address.parse().unwrap()
+    }
+
+    fn redirected(protocol: u8) -> Vec<Vec<u8>> {
+        vec![
+            tuple(CTA_TUPLE_ORIG, protocol, address("192.168.1.5:40000"), address("93.184.216.34:443")),
+            tuple(CTA_TUPLE_REPLY, protocol, address("127.0.0.1:14889"), address("192.168.1.5:40000")),
+        ]
+    }
+
+    #[test]
+    fn parses_a_redirected_connection() {
+        let mut attributes = redirected(IPPROTO_TCP);
+        attributes.extend([
+            attribute(CTA_STATUS, &(IPS_ASSURED | 0x8).to_be_bytes()),
+            tcp_state(3),
+            counters(CTA_COUNTERS_ORIG, 10, 1_000),
+            counters(CTA_COUNTERS_REPLY, 20, 50_000),
+            attribute(CTA_ID, &42u32.to_be_bytes()),
+            nested(CTA_TIMESTAMP, &[attribute(CTA_TIMESTAMP_START, &1_700_000_000_000_000_000u64.to_be_bytes())]),
+        ]);
+
+        let connection = parse_connection(CT_NEW, &payload(&attributes)).unwrap();
+        assert_eq!(connection.id, 42);
+        assert_eq!(connection.destination, address("93.184.216.34:443"));
+        assert_eq!(connection.reply_source, address("127.0.0.1:14889"));
+        assert_eq!((connection.bytes_up, connection.bytes_down, connection.packets_down), (1_000, 50_000, 20));
+        assert!(connection.assured);
+        assert!(connection.open);
+        assert_eq!(connection.started, Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+    }
+
+    #[test]
+    fn missing_attributes_have_defaults() {
+        let connection = parse_connection(CT_NEW, &payload(&redirected(IPPROTO_TCP))).unwrap();
+        assert_eq!((connection.id, connection.bytes_up, connection.bytes_down), (0, 0, 0));
+        assert!(!connection.assured);
+        // without a TCP state the connection is taken to be open
+        assert!(connection.open);
+        assert_eq!(connection.started, None);
+    }
+
+    #[test]
+    fn closed_connections() {
+        for state in [TCP_CONNTRACK_TIME_WAIT, TCP_CONNTRACK_CLOSE] {
+            let mut attributes = redirected(IPPROTO_TCP);
+            attributes.push(tcp_state(state));
+            assert!(!parse_connection(CT_NEW, &payload(&attributes)).unwrap().open);
+        }
+    }
+
+    #[test]
+    fn ignores_other_messages_and_protocols() {
+        let tcp = payload(&redirected(IPPROTO_TCP));
+        assert!(parse_connection((NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_DELETE, &tcp).is_some());
+        assert!(parse_connection((NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_GET, &tcp).is_none());
+        assert!(parse_connection((2 << 8) | IPCTNL_MSG_CT_NEW, &tcp).is_none());
+        assert!(parse_connection(NLMSG_DONE, &tcp).is_none());
+
+        // UDP
+        assert!(parse_connection(CT_NEW, &payload(&redirected(17))).is_none());
+        // no reply tuple
+        assert!(parse_connection(CT_NEW, &payload(&redirected(IPPROTO_TCP)[..1])).is_none());
+        assert!(parse_connection(CT_NEW, &[]).is_none());
+    }
+
+    #[test]
+    fn splits_messages() {
+        // 3 bytes of payload are padded to 4
+        let first = message(CT_NEW, &[1, 2, 3]);
+        let mut buffer = first.clone();
+        buffer.push(0);
+        buffer.extend(message(NLMSG_DONE, &[4, 5, 6, 7]));
+
+        let found: Vec<(u16, &[u8])> = messages(&buffer).collect();
+        assert_eq!(found, [(CT_NEW, &[1u8, 2, 3][..]), (NLMSG_DONE, &[4u8, 5, 6, 7][..])]);
+
+        // a length past the end of the datagram ends it
+        let mut truncated = message(CT_NEW, &[1, 2, 3, 4]);
+        truncated[0] = 200;
+        assert_eq!(messages(&truncated).count(), 0);
+        assert_eq!(messages(&first[..15]).count(), 0);
+    }
+
+    #[test]
+    fn splits_attributes() {
+        let buffer = [attribute(1, &[0xaa]), nested(2, &[attribute(3, &[1, 2, 3, 4])])].concat();
+        let found: Vec<(u16, &[u8])> = attributes(&buffer).collect();
+        // the nested flag is masked out, padding isn't part of the value
+        assert_eq!(found, [(1, &[0xaau8][..]), (2, &attribute(3, &[1, 2, 3, 4])[..])]);
+        assert_eq!(find(found[1].1, 3), Some(&[1u8, 2, 3, 4][..]));
+        assert_eq!(find(&buffer, 4), None);
+
+        // an attribute shorter than its header ends the list
+        assert_eq!(attributes(&[2, 0, 1, 0]).count(), 0);
+        assert_eq!(attributes(&buffer[..4]).count(), 0);
+    }
+}
@@ -0,0 +1,612 @@
+use crate::audit::{self, Action, Record};
+use crate::bindings;
+use crate::client::Client;
+use crate::conntrack::{self, DestroyEvents};
+use crate::health;
+use crate::loader;
+use crate::metrics::{self, Counters, Snapshot};
+use crate::network::{self, NetworkRule, RouteWatcher};
+use crate::paths::*;
+use crate::privileges::{self, Backend};
+use crate::rpc::{self, ConfigurationSummary, Event, Message, ProxyStatus, Request, RpcError, Statistics, Status};
+use crate::schedule;
+use crate::settings::Settings;
+use crate::state;
+use crate::traffic::Accounting;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+// a new connection changes routes and addresses several times before it is usable
+const NETWORK_SETTLE: Duration = Duration::from_secs(2);
+// how soon a switch that failed is tried again on the same network
+const SWITCH_RETRY: Duration = Duration::from_secs(30);
+
+struct Daemon {
+    // locked for the whole activation, so concurrent requests are applied one after another
+    status: Mutex<Status>,
+    events: broadcast::Sender<Event>,
+    recheck: Notify,
+    // what made the current activation, changed under the status lock
+    origin: Mutex<Origin>,
+    // wakes auto_switch when a schedule window ends, so the network rule applies again
+    schedule_ended: Notify,
+    traffic: Mutex<Accounting>,
+    counters: Mutex<Counters>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    Manual,
+    Schedule,
+    Network,
+}
+
+/// Serves the JSON-RPC API on `SOCKET_PATH` until SIGINT or SIGTERM, and Prometheus metrics on
+/// `metrics` or the address from the settings if either is given.
+pub async fn run(metrics: Option<SocketAddr>) -> anyhow::Result<()> {
+    if Client::connect().await.is_ok() {
+        anyhow::bail!("proxswapd is already listening on {}", *SOCKET_PATH);
+    }
+
+    // left behind by a daemon that didn't exit cleanly
+    let _ = std::fs::remove_file(&*SOCKET_PATH);
+    let listener = UnixListener::bind(&*SOCKET_PATH)
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", *SOCKET_PATH, e))?;
+    std::fs::set_permissions(&*SOCKET_PATH, std::fs::Permissions::from_mode(0o600))?;
+
+    let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+    let active_index = state::find_active_config(&configurations);
+    let active = active_index.map(|index| configurations[index].name.clone());
+    let settings = Settings::load().unwrap_or_else(|e| {
+        eprintln!("Ignoring {}: {}", *SETTINGS_PATH, e);
+        Settings::default()
+    });
+    let mut traffic = Accounting::new(settings.conntrack_accounting);
+    traffic.set_active(active_index.map(|index| &configurations[index]));
+    let (events, _) = broadcast::channel(64);
+    let daemon = Arc::new(Daemon {
+        status: Mutex::new(Status {
+            active,
+            proxies: Vec::new(),
+        }),
+        events,
+        recheck: Notify::new(),
+        origin: Mutex::new(Origin::Manual),
+        schedule_ended: Notify::new(),
+        traffic: Mutex::new(traffic),
+        counters: Mutex::new(Counters::default()),
+    });
+
+    tokio::spawn(check_health(daemon.clone()));
+    tokio::spawn(follow_schedules(daemon.clone()));
+    tokio::spawn(count_traffic(daemon.clone()));
+    eprintln!("proxswapd listening on {}", *SOCKET_PATH);
+
+    if !settings.auto_switch.is_empty() {
+        tokio::spawn(auto_switch(daemon.clone(), settings.auto_switch));
+    }
+    if let Some(address) = metrics.or(settings.metrics_address) {
+        let scraped = daemon.clone();
+        metrics::serve(address, move || {
+            let daemon = scraped.clone();
+            async move { daemon.metrics().await }
+        })
+        .await?;
+        eprintln!("Metrics are served on http://{}/metrics", address);
+    }
+
+    let backend = privileges::backend().await;
+    eprintln!("Rules are managed with {}", backend);
+    if backend == Backend::Sudo {
+        eprintln!("sudo can't ask for a password here. {}.", privileges::MISSING_PRIVILEGES);
+    }
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(daemon.clone(), stream));
+                }
+                Err(e) => eprintln!("Failed to accept a connection: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+
+    let _ = std::fs::remove_file(&*SOCKET_PATH);
+    Ok(())
+}
+
+async fn handle_client(daemon: Arc<Daemon>, stream: UnixStream) {
+    // the socket is only accessible to its owner, but root can connect too
+    let user = match stream.peer_cred() {
+        Ok(credentials) => audit::user_name(credentials.uid()),
+        Err(_) => audit::current_user(),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+
+    // responses and events share the connection, so a single task writes both
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = outgoing_rx.recv().await {
+            let Ok(mut line) = serde_json::to_string(&message) else {
+                continue;
+            };
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match rpc::parse_request(&line) {
+            Ok(request) => {
+                let id = request.id.clone();
+                let result = daemon.handle(request, &user, &outgoing).await;
+                // notifications get no response, not even an error
+                let Some(id) = id else {
+                    continue;
+                };
+                Message::response(id, result)
+            }
+            Err((id, error)) => Message::response(id, Err(error)),
+        };
+
+        if outgoing.send(message).is_err() {
+            break;
+        }
+    }
+
+    // subscriptions notice the closed channel on their next event
+    writer_task.abort();
+}
+
+impl Daemon {
+    async fn handle(
+        &self,
+        request: Request,
+        user: &str,
+        outgoing: &mpsc::UnboundedSender<Message>,
+    ) -> Result<Value, RpcError> {
+        match request.method.as_str() {
+            "list" => self.list().await,
+            "activate" => {
+                let name = request
+                    .params
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "expected {\"name\": <configuration>}"))?;
+                self.activate(name, user, Origin::Manual).await
+            }
+            "deactivate" => self.deactivate(user).await,
+            "status" => to_value(&*self.status.lock().await),
+            "stats" => {
+                let statistics = self.statistics().await.map_err(|e| {
+                    RpcError::new(rpc::INTERNAL_ERROR, format!("Failed to read the conntrack table: {}", e))
+                })?;
+                to_value(&statistics)
+            }
+            "subscribe" => {
+                self.subscribe(outgoing.clone());
+                Ok(Value::Bool(true))
+            }
+            other => Err(RpcError::new(rpc::METHOD_NOT_FOUND, format!("Unknown method \"{}\"", other))),
+        }
+    }
+
+    async fn list(&self) -> Result<Value, RpcError> {
+        let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+        let active = self.status.lock().await.active.clone();
+
+        let summaries: Vec<ConfigurationSummary> = configurations
+            .iter()
+            .map(|config| ConfigurationSummary {
+                name: config.name.clone(),
+                proxies: config.proxies.len(),
+                rules: config.rules.len(),
+                active: active.as_deref() == Some(config.name.as_str()),
+            })
+            .collect();
+
+        to_value(&summaries)
+    }
+
+    /// Applies the configuration called `name` on behalf of `user`, who is named in the history.
+    /// `origin` is what asked for it, see `follow_schedules` and `auto_switch`.
+    async fn activate(&self, name: &str, user: &str, origin: Origin) -> Result<Value, RpcError> {
+        let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+        let Some(config) = configurations.into_iter().find(|config| config.name == name) else {
+            let error = RpcError::new(rpc::NOT_FOUND, format!("No configuration named \"{}\"", name));
+            log(Record::new(user, Action::Activate).name(Some(name)).outcome(&Err::<(), _>(&error.message)));
+            return Err(error);
+        };
+
+        let mut status = self.status.lock().await;
+        bindings::deactivate_proxy().await;
+        *self.origin.lock().await = origin;
+
+        let result = config.run().await;
+        {
+            let mut counters = self.counters.lock().await;
+            // redsocks was stopped above, it runs again if the activation got past starting it
+            let started = match &result {
+                Ok(_) => true,
+                Err(e) => e.is::<bindings::RulesError>(),
+            };
+            if started {
+                *counters.redirector_restarts.entry(name.to_string()).or_default() += 1;
+            }
+            if result.is_err() {
+                *counters.activation_failures.entry(name.to_string()).or_default() += 1;
+            }
+        }
+
+        log(Record::new(user, Action::Activate).configuration(&config).outcome(&result));
+
+        if let Err(e) = result {
+            // the previous configuration is gone already
+            *status = Status::default();
+            self.traffic.lock().await.set_active(None);
+            let _ = state::write_active_config(None);
+            let _ = self.events.send(Event::Deactivated);
+            return Err(RpcError::new(rpc::ACTIVATION_FAILED, e.to_string()));
+        }
+
+        *status = Status {
+            active: Some(name.to_string()),
+            proxies: Vec::new(),
+        };
+        self.traffic.lock().await.set_active(Some(&config));
+        let _ = state::write_active_config(Some(name));
+        let _ = self.events.send(Event::Activated { name: name.to_string() });
+        self.recheck.notify_one();
+
+        Ok(Value::Null)
+    }
+
+    async fn deactivate(&self, user: &str) -> Result<Value, RpcError> {
+        let mut status = self.status.lock().await;
+        bindings::deactivate_proxy().await;
+        // nothing is left for a schedule to end
+        *self.origin.lock().await = Origin::Manual;
+        log(Record::new(user, Action::Deactivate).name(status.active.as_deref()));
+
+        *status = Status::default();
+        self.traffic.lock().await.set_active(None);
+        let _ = state::write_active_config(None);
+        let _ = self.events.send(Event::Deactivated);
+
+        Ok(Value::Null)
+    }
+
+    // the dump can take a while, the accounting is only locked to add it up
+    async fn statistics(&self) -> std::io::Result<Statistics> {
+        let connections = conntrack::dump().await?;
+        Ok(self.traffic.lock().await.statistics(connections))
+    }
+
+    async fn metrics(&self) -> String {
+        let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+        let names: Vec<String> = configurations.into_iter().map(|config| config.name).collect();
+        let status = self.status.lock().await.clone();
+        // without access to conntrack the traffic metrics are left out
+        let statistics = self.statistics().await.ok();
+        let totals = self.traffic.lock().await.totals();
+        let counters = self.counters.lock().await;
+
+        metrics::render(&Snapshot {
+            configurations: &names,
+            status: &status,
+            statistics: statistics.as_ref(),
+            totals: &totals,
+            counters: &counters,
+        })
+    }
+
+    fn subscribe(&self, outgoing: mpsc::UnboundedSender<Message>) {
+        let mut events = self.events.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if outgoing.send(Message::event(&event)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+// checks the active configuration's proxies periodically and right after every activation
+async fn check_health(daemon: Arc<Daemon>) {
+    loop {
+        let active = daemon.status.lock().await.active.clone();
+
+        if let Some(name) = active {
+            let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+
+            if let Some(config) = configurations.iter().find(|config| config.name == name) {
+                let results = health::check_proxies(&config.proxies, HEALTH_TIMEOUT).await;
+                let proxies: Vec<ProxyStatus> = config
+                    .proxies
+                    .iter()
+                    .zip(results)
+                    .map(|(proxy, result)| ProxyStatus {
+                        url: proxy.url.clone(),
+                        port: proxy.port,
+                        latency_ms: result.as_ref().ok().map(|latency| latency.as_millis() as u64),
+                        error: result.err().map(|e| e.to_string()),
+                    })
+                    .collect();
+
+                let mut status = daemon.status.lock().await;
+                // another configuration may have been activated while the proxies were checked
+                if status.active.as_deref() == Some(name.as_str()) {
+                    status.proxies = proxies.clone();
+                    let _ = daemon.events.send(Event::Health { name, proxies });
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(HEALTH_INTERVAL) => {}
+            _ = daemon.recheck.notified() => {}
+        }
+    }
+}
+
+// adds connections to the totals as the kernel forgets them, open ones are read when asked for
+async fn count_traffic(daemon: Arc<Daemon>) {
+    let mut destroyed = match DestroyEvents::new() {
+        Ok(destroyed) => destroyed,
+        Err(e) => {
+            eprintln!("Traffic statistics are off, failed to watch conntrack: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        match destroyed.next().await {
+            Ok(connections) => {
+                let mut traffic = daemon.traffic.lock().await;
+                for connection in connections.iter() {
+                    traffic.finished(connection);
+                }
+            }
+            Err(e) => {
+                eprintln!("Traffic statistics stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// switches when a window starts or ends rather than enforcing the schedule every minute, so a
+// manual activation or deactivation holds until the next window starts. Only an activation the
+// schedule made is ended with its window.
+async fn follow_schedules(daemon: Arc<Daemon>) {
+    let user = format!("{} (schedule)", audit::current_user());
+    let mut in_window: HashSet<String> = HashSet::new();
+
+    loop {
+        let (configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+        let now = schedule::minute_of_week();
+        let current: HashSet<String> = configurations
+            .iter()
+            .filter(|config| config.schedule.contains(now))
+            .map(|config| config.name.clone())
+            .collect();
+
+        let active = daemon.status.lock().await.active.clone();
+        let scheduled = *daemon.origin.lock().await == Origin::Schedule;
+        if let Some(name) = active.filter(|name| scheduled && in_window.contains(name) && !current.contains(name)) {
+            eprintln!("Schedule of {} ended, deactivating", name);
+            let _ = daemon.deactivate(&user).await;
+            daemon.schedule_ended.notify_one();
+        }
+
+        // if several windows start at once, the first configuration wins
+        if let Some(config) = configurations
+            .iter()
+            .find(|config| current.contains(&config.name) && !in_window.contains(&config.name))
+        {
+            eprintln!("Schedule of {} started, activating", config.name);
+            if let Err(e) = daemon.activate(&config.name, &user, Origin::Schedule).await {
+                eprintln!("Failed to activate {}: {}", config.name, e.message);
+            }
+        }
+
+        in_window = current;
+
+        // wake up at the start of the next minute, windows are given in minutes
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() % 60)
+            .unwrap_or_default();
+        tokio::time::sleep(Duration::from_secs(60 - seconds)).await;
+    }
+}
+
+// activates the configuration of the first matching rule whenever the network changes. Only a change
+// of the matching rule switches, so a configuration picked by hand stays until the next network.
+// An open schedule window has precedence, the rule is applied once it ends.
+async fn auto_switch(daemon: Arc<Daemon>, rules: Vec<NetworkRule>) {
+    let mut watcher = match RouteWatcher::new() {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Automatic switching is off, failed to watch routes: {}", e);
+            return;
+        }
+    };
+    let user = format!("{} (network)", audit::current_user());
+    let mut matched: Option<usize> = None;
+
+    loop {
+        let current = network::current_network().await;
+        let index = rules.iter().position(|rule| rule.matches(&current));
+
+        match index.filter(|&index| matched != Some(index)) {
+            // left unmatched, so it is tried again when the window ends
+            Some(_) if *daemon.origin.lock().await == Origin::Schedule => {}
+            Some(index) => {
+                let rule = &rules[index];
+                eprintln!("Network changed, switching to {}", rule.target());
+                let switched = match &rule.configuration {
+                    Some(name) => daemon.activate(name, &user, Origin::Network).await,
+                    None => daemon.deactivate(&user).await,
+                };
+                // a failed switch is tried again, on a timer or the next change
+                match switched {
+                    Ok(_) => matched = Some(index),
+                    Err(e) => eprintln!("Failed to switch to {}: {}", rule.target(), e.message),
+                }
+            }
+            None => matched = index,
+        }
+
+        let failed = matched != index && *daemon.origin.lock().await != Origin::Schedule;
+        tokio::select! {
+            changed = watcher.changed() => {
+                if let Err(e) = changed {
+                    eprintln!("Automatic switching stopped: {}", e);
+                    return;
+                }
+                tokio::time::sleep(NETWORK_SETTLE).await;
+                watcher.drain();
+            }
+            _ = tokio::time::sleep(SWITCH_RETRY), if failed => {}
+            _ = daemon.schedule_ended.notified() => {}
+        }
+    }
+}
+
+fn log(record: Record) {
+    if let Err(e) = record.append() {
+        eprintln!("Failed to write to {}: {}", *AUDIT_LOG_PATH, e);
+    }
+}
+
+fn to_value<T: serde::Serialize>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(rpc::INTERNAL_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::Lines;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+    // a client connected to a daemon that hasn't activated anything, methods that touch the
+    // configurations or the rules are left out
+    fn connect() -> (Arc<Daemon>, OwnedWriteHalf, Lines<BufReader<OwnedReadHalf>>) {
+        let (events, _) = broadcast::channel(8);
+        let daemon = Arc::new(Daemon {
+            status: Mutex::new(Status::default()),
+            events,
+            recheck: Notify::new(),
+            origin: Mutex::new(Origin::Manual),
+            schedule_ended: Notify::new(),
+            traffic: Mutex::new(Accounting::new(false)),
+            counters: Mutex::new(Counters::default()),
+        });
+
+        let (client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(handle_client(daemon.clone(), server));
+        let (reader, writer) = client.into_split();
+        (daemon, writer, BufReader::new(reader).lines())
+    }
+
+    async fn send(writer: &mut OwnedWriteHalf, line: &str) {
+        writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+    }
+
+    async fn receive(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_get_responses() {
+        let (_daemon, mut writer, mut lines) = connect();
+
+        send(&mut writer, r#"{"jsonrpc": "2.0", "id": 1, "method": "status"}"#).await;
+        assert_eq!(
+            receive(&mut lines).await,
+            json!({ "jsonrpc": "2.0", "id": 1, "result": { "active": null, "proxies": [] } })
+        );
+
+        send(&mut writer, r#"{"jsonrpc": "2.0", "id": "x", "method": "restart"}"#).await;
+        let response = receive(&mut lines).await;
+        assert_eq!(response["id"], json!("x"));
+        assert_eq!(response["error"]["code"], json!(rpc::METHOD_NOT_FOUND));
+
+        send(&mut writer, r#"{"jsonrpc": "2.0", "id": 2, "method": "activate", "params": {}}"#).await;
+        assert_eq!(receive(&mut lines).await["error"]["code"], json!(rpc::INVALID_PARAMS));
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_get_errors() {
+        let (_daemon, mut writer, mut lines) = connect();
+
+        send(&mut writer, "not json").await;
+        let response = receive(&mut lines).await;
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], json!(rpc::PARSE_ERROR));
+
+        send(&mut writer, r#"{"jsonrpc": "1.0", "id": 7, "method": "status"}"#).await;
+        let response = receive(&mut lines).await;
+        assert_eq!(response["id"], json!(7));
+        assert_eq!(response["error"]["code"], json!(rpc::INVALID_REQUEST));
+        assert!(response.get("result").is_none());
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let (_daemon, mut writer, mut lines) = connect();
+
+        // even a failing notification stays unanswered, the next response is for the request
+        send(&mut writer, r#"{"jsonrpc": "2.0", "method": "status"}"#).await;
+        send(&mut writer, r#"{"jsonrpc": "2.0", "method": "restart"}"#).await;
+        send(&mut writer, "").await;
+        send(&mut writer, r#"{"jsonrpc": "2.0", "id": 3, "method": "status"}"#).await;
+        assert_eq!(receive(&mut lines).await["id"], json!(3));
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_events() {
+        let (daemon, mut writer, mut lines) = connect();
+
+        send(&mut writer, r#"{"jsonrpc": "2.0", "id": 1, "method": "subscribe"}"#).await;
+        assert_eq!(receive(&mut lines).await, json!({ "jsonrpc": "2.0", "id": 1, "result": true }));
+
+        daemon.events.send(Event::Activated { name: "office".to_string() }).unwrap();
+        assert_eq!(
+            receive(&mut lines).await,
+            json!({ "jsonrpc": "2.0", "method": "event", "params": { "type": "activated", "name": "office" } })
+        );
+    }
+}
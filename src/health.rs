@@ -0,0 +1,28 @@
+use crate::configuration::Proxy;
+use futures::stream::{self, StreamExt};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+// how many proxies are probed at the same time
+const CONCURRENT_CHECKS: usize = 32;
+
+/// Checks that the proxy accepts TCP connections, returning how long connecting took.
+pub async fn check_proxy(proxy: &Proxy, timeout: Duration) -> anyhow::Result<Duration> {
+    let started = Instant::now();
+
+    tokio::time::timeout(timeout, TcpStream::connect((proxy.url.as_str(), proxy.port)))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {}ms", timeout.as_millis()))??;
+
+    Ok(started.elapsed())
+}
+
+/// Runs `check_proxy` for every proxy, results are in the same order as `proxies`.
+pub async fn check_proxies(proxies: &[Proxy], timeout: Duration) -> Vec<anyhow::Result<Duration>> {
+    // owned proxies keep the future Send, so it can run on a spawned task
+    stream::iter(proxies.iter().cloned())
+        .map(|proxy| async move { check_proxy(&proxy, timeout).await })
+        .buffered(CONCURRENT_CHECKS)
+        .collect()
+        .await
+}
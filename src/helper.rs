@@ -0,0 +1,213 @@
+use crate::bindings;
+use crate::client::Client;
+use crate::configuration::{write_atomic, Configuration};
+use crate::paths::*;
+use crate::privileges::{self, CAP_NET_ADMIN};
+use crate::rpc::{self, Message, Request, RpcError};
+use serde_json::{json, Value};
+use std::ffi::{CStr, CString};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+
+// a redsocks started by root runs as this user once it listens
+const REDSOCKS_USER: &str = "nobody";
+
+/// Runs the privileged helper. It only applies configurations it validated itself and builds
+/// every redsocks config and iptables command on its own, so the callers never need sudo.
+pub async fn run(mut allowed_uids: Vec<u32>) -> anyhow::Result<()> {
+    if !privileges::is_root() && !privileges::has_capability(CAP_NET_ADMIN) {
+        anyhow::bail!("The helper has to run as root or with CAP_NET_ADMIN, e.g. `sudo proxswap helper`");
+    }
+
+    // the user that started the helper through sudo is the one that's going to use it
+    if let Some(uid) = std::env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        allowed_uids.push(uid);
+    }
+    allowed_uids.push(0);
+    allowed_uids.push(unsafe { libc::getuid() });
+
+    std::fs::create_dir_all(&*HELPER_DIR)?;
+    std::fs::set_permissions(&*HELPER_DIR, std::fs::Permissions::from_mode(0o755))?;
+    let _ = std::fs::remove_file(&*HELPER_SOCKET_PATH);
+    let listener = UnixListener::bind(&*HELPER_SOCKET_PATH)
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", *HELPER_SOCKET_PATH, e))?;
+    // anyone may connect, requests are checked against the caller's uid
+    std::fs::set_permissions(&*HELPER_SOCKET_PATH, std::fs::Permissions::from_mode(0o666))?;
+
+    eprintln!("proxswap helper listening on {}, allowed uids: {:?}", *HELPER_SOCKET_PATH, allowed_uids);
+
+    let allowed_uids = Arc::new(allowed_uids);
+    // one activation at a time, whoever is asking
+    let busy = Arc::new(Mutex::new(()));
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_client(stream, allowed_uids.clone(), busy.clone()));
+                }
+                Err(e) => eprintln!("Failed to accept a connection: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+
+    let _ = std::fs::remove_file(&*HELPER_SOCKET_PATH);
+    Ok(())
+}
+
+async fn handle_client(stream: UnixStream, allowed_uids: Arc<Vec<u32>>, busy: Arc<Mutex<()>>) {
+    let uid = stream.peer_cred().ok().map(|credentials| credentials.uid());
+    let allowed = uid.is_some_and(|uid| allowed_uids.contains(&uid));
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match rpc::parse_request(&line) {
+            // notifications get no response, not even an error
+            Ok(Request { id: None, .. }) if !allowed => continue,
+            Ok(Request { id: Some(id), .. }) if !allowed => Message::response(
+                id,
+                Err(RpcError::new(
+                    rpc::NOT_ALLOWED,
+                    format!("uid {} isn't allowed to use the helper, see --allow-uid", uid.unwrap_or(u32::MAX)),
+                )),
+            ),
+            Ok(request) => {
+                let id = request.id.clone();
+                let _busy = busy.lock().await;
+                let result = handle(request).await;
+                let Some(id) = id else {
+                    continue;
+                };
+                Message::response(id, result)
+            }
+            Err((id, error)) => Message::response(id, Err(error)),
+        };
+
+        let Ok(mut line) = serde_json::to_string(&message) else {
+            continue;
+        };
+        line.push('\n');
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn handle(request: Request) -> Result<Value, RpcError> {
+    match request.method.as_str() {
+        "ping" => Ok(Value::Bool(true)),
+        "activate" => {
+            let config: Configuration = request
+                .params
+                .get("configuration")
+                .cloned()
+                .ok_or_else(|| RpcError::new(rpc::INVALID_PARAMS, "expected {\"configuration\": ...}"))
+                .and_then(|config| {
+                    serde_json::from_value(config).map_err(|e| RpcError::new(rpc::INVALID_PARAMS, e.to_string()))
+                })?;
+            config
+                .validate(std::iter::empty())
+                .map_err(|e| RpcError::new(rpc::INVALID_PARAMS, e.to_string()))?;
+
+            apply(&config).await.map_err(|e| {
+                let code = if e.is::<bindings::RulesError>() { rpc::RULES_FAILED } else { rpc::ACTIVATION_FAILED };
+                RpcError::new(code, e.to_string())
+            })?;
+            Ok(Value::Null)
+        }
+        "deactivate" => {
+            bindings::kill_redsocks().await;
+            bindings::flush_rules().await;
+            Ok(Value::Null)
+        }
+        other => Err(RpcError::new(rpc::METHOD_NOT_FOUND, format!("Unknown method \"{}\"", other))),
+    }
+}
+
+async fn apply(config: &Configuration) -> anyhow::Result<()> {
+    bindings::kill_redsocks().await;
+    bindings::flush_rules().await;
+
+    // the socket's directory has to stay readable, the credentials in the redsocks config don't
+    let redsocks_dir = format!("{}/redsocks", *HELPER_DIR);
+    std::fs::create_dir_all(&redsocks_dir)?;
+    std::fs::set_permissions(&redsocks_dir, std::fs::Permissions::from_mode(0o700))?;
+
+    let redsocks_config = format!("{}/redsocks.conf", redsocks_dir);
+    // without root redsocks can't switch users, it runs as the helper's user
+    let contents = if privileges::is_root() {
+        let group = primary_group(REDSOCKS_USER);
+        config.redsocks_config_as(Some((REDSOCKS_USER, group.as_deref())))
+    } else {
+        config.redsocks_config()
+    };
+    write_atomic(Path::new(&redsocks_config), contents.as_bytes())?;
+    bindings::start_redsocks(Path::new(&redsocks_config)).await?;
+
+    bindings::apply_rules(&config.rules).await.map_err(|e| bindings::RulesError(e).into())
+}
+
+// name of the group of `user`, it is "nobody" or "nogroup" depending on the distribution
+fn primary_group(user: &str) -> Option<String> {
+    let user = CString::new(user).ok()?;
+
+    unsafe {
+        let mut buffer = vec![0 as libc::c_char; 4096];
+        let mut passwd: libc::passwd = std::mem::zeroed();
+        let mut found: *mut libc::passwd = std::ptr::null_mut();
+        libc::getpwnam_r(user.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut found);
+        if found.is_null() {
+            return None;
+        }
+        let gid = passwd.pw_gid;
+
+        let mut group: libc::group = std::mem::zeroed();
+        let mut found: *mut libc::group = std::ptr::null_mut();
+        libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut found);
+        if found.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(group.gr_name).to_string_lossy().into_owned())
+    }
+}
+
+/// Whether a helper answers on `HELPER_SOCKET_PATH`.
+pub async fn is_running() -> bool {
+    call("ping", Value::Null).await.is_ok()
+}
+
+pub async fn activate(config: &Configuration) -> anyhow::Result<()> {
+    match call("activate", json!({ "configuration": config })).await {
+        Ok(_) => Ok(()),
+        // keeps telling the caller that redsocks runs
+        Err(e) => match e.downcast::<RpcError>() {
+            Ok(error) if error.code == rpc::RULES_FAILED => Err(bindings::RulesError(error.into()).into()),
+            Ok(error) => Err(error.into()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+pub async fn deactivate() -> anyhow::Result<()> {
+    call("deactivate", Value::Null).await?;
+    Ok(())
+}
+
+async fn call(method: &str, params: Value) -> anyhow::Result<Value> {
+    let mut client = Client::connect_to(&HELPER_SOCKET_PATH).await?;
+    client.call(method, params).await
+}
@@ -0,0 +1,110 @@
+use crate::configuration::Configuration;
+use crate::migration;
+use crate::validation;
+use serde_json::from_reader;
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// A configuration file that couldn't be loaded, with the position of the error when it's known.
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub path: PathBuf,
+    pub message: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl LoadError {
+    fn new(path: &Path, error: anyhow::Error) -> Self {
+        // serde_json reports line 0 for errors that aren't tied to a position, e.g. I/O
+        let position = error
+            .downcast_ref::<serde_json::Error>()
+            .filter(|e| e.line() > 0)
+            .map(|e| (e.line(), e.column()));
+
+        LoadError {
+            path: path.to_path_buf(),
+            message: error.to_string(),
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+        }
+    }
+
+    pub fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.to_string_lossy().to_string())
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.path.display(),
+                line,
+                column,
+                self.message
+            ),
+            _ => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Loads every `*.json` file in `dir_path`, collecting the files that fail instead of giving up.
+pub async fn load_configurations_dir(dir_path: &str) -> (Vec<Configuration>, Vec<LoadError>) {
+    let mut configurations: Vec<Configuration> = Vec::new();
+    let mut errors: Vec<LoadError> = Vec::new();
+
+    let entries = match create_dir_all(dir_path).and_then(|_| read_dir(dir_path)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(LoadError::new(Path::new(dir_path), e.into()));
+            return (configurations, errors);
+        }
+    };
+
+    let mut matching_files: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect();
+    matching_files.sort();
+
+    for file in matching_files.iter() {
+        match load_configuration(file).await {
+            Ok(configuration) => configurations.push(configuration),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (configurations, errors)
+}
+
+/// Reads a configuration without writing anything back, apart from upgrading an outdated schema.
+pub async fn load_configuration(file_path: &Path) -> Result<Configuration, LoadError> {
+    read_configuration(file_path).map_err(|e| LoadError::new(file_path, e))
+}
+
+fn read_configuration(file_path: &Path) -> anyhow::Result<Configuration> {
+    migration::migrate_file(file_path)?;
+
+    let file = File::open(file_path)?;
+    let reader = BufReader::new(file);
+    let config: Configuration = from_reader(reader)?;
+
+    // the name decides where the configuration is saved and deleted, so it has to be this file
+    validation::validate_name(&config.name, std::iter::empty())?;
+    if file_path.file_stem() != Some(config.name.as_ref()) {
+        anyhow::bail!("the name \"{}\" doesn't match the file name", config.name);
+    }
+
+    Ok(config)
+}
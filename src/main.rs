@@ -1,12 +1,37 @@
+mod audit;
 mod configuration;
-use crate::configuration::Configuration;
-use anyhow::Ok;
-use serde_json::from_reader;
-use std::fs::{read_dir, File, create_dir_all};
-use std::io::BufReader;
+mod conntrack;
+mod export;
+mod health;
+mod helper;
+use clap::Parser;
+use std::fs::create_dir_all;
+use std::path::Path;
 mod bindings;
+mod clash;
+mod cli;
+mod client;
+mod daemon;
+mod loader;
+mod metrics;
+mod traffic;
+mod template;
+mod trash;
 mod tui;
+mod migration;
+mod network;
+mod nftables;
 mod paths;
+mod privileges;
+mod state;
+mod proxy_list;
+mod restore;
+mod schedule;
+mod search;
+mod rpc;
+mod settings;
+mod uri;
+mod validation;
 use paths::*;
 
 
@@ -16,37 +41,51 @@ async fn make_config_directories() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn init_configurations_dir(dir_path: &str) -> Vec<Configuration> {
-    let mut configurations: Vec<Configuration> = Vec::new();
+#[tokio::main]
+async fn main() {
+    make_config_directories().await.unwrap();
 
-    let matching_files: Vec<String> = read_dir(dir_path)
-        .expect("Failed to read directory")
-        .filter_map(Result::ok)
-        .filter(|entry| entry.path().extension().and_then(|e| e.to_str()) == Some("json"))
-        .map(|entry| entry.path().to_string_lossy().to_string())
-        .collect();
+    // installed as a proxswapd link, the binary runs the daemon
+    let invoked_as = std::env::args().next().unwrap_or_default();
+    let cli = if Path::new(&invoked_as).file_name().is_some_and(|name| name == "proxswapd") {
+        cli::Cli {
+            command: Some(cli::Command::Daemon { metrics: None }),
+            on_exit: None,
+        }
+    } else {
+        cli::Cli::parse()
+    };
 
-    for file in matching_files.iter() {
-        configurations.push(init_configuration(file.to_string()).await);
+    if let Some(command) = cli.command {
+        if let Err(e) = cli::run(command).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    configurations
-}
-
-
-async fn init_configuration(file_path: String) -> Configuration {
-    let file = File::open(file_path).expect("Failed to open file");
-    let reader = BufReader::new(file);
-    let config: Configuration = from_reader(reader).expect("Failed to parse JSON");
+    let settings = settings::Settings::load().unwrap_or_else(|e| {
+        eprintln!("Ignoring {}: {}", *SETTINGS_PATH, e);
+        settings::Settings::default()
+    });
+    let exit_policy = cli.on_exit.unwrap_or(settings.on_exit);
 
-    Configuration::new(config.name, config.proxies, config.rules).await
-}
-
-#[tokio::main]
-async fn main() {
-    make_config_directories().await.unwrap();
+    let (configurations, load_errors) = loader::load_configurations_dir(&CONFIG_DIR).await;
+    // on its own task, so a panic in the TUI still ends with the exit policy applied
+    let app = tokio::spawn(async move {
+        let mut app = tui::App::new(configurations, load_errors, exit_policy);
+        app.run().await.map_err(|e| e.to_string())
+    });
 
-    let configurations = init_configurations_dir(&CONFIG_DIR).await;
-    let mut app = tui::App::new(configurations);
-    app.run().await.expect("Failed to run application");
+    match app.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            tui::apply_exit_policy(exit_policy, true).await;
+            std::process::exit(101);
+        }
+    }
 }
@@ -0,0 +1,345 @@
+use crate::rpc::{ConfigurationTraffic, Statistics, Status};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters proxswapd keeps for the metrics, by configuration name.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub activation_failures: BTreeMap<String, u64>,
+    pub redirector_restarts: BTreeMap<String, u64>,
+}
+
+/// Everything one scrape reports.
+pub struct Snapshot<'a> {
+    pub configurations: &'a [String],
+    pub status: &'a Status,
+    /// None if the conntrack table can't be read
+    pub statistics: Option<&'a Statistics>,
+    /// Totals of the finished connections, the counters are made from these so they don't go
+    /// down when a connection's end is missed
+    pub totals: &'a [ConfigurationTraffic],
+    pub counters: &'a Counters,
+}
+
+/// Serves `render()` at `/metrics` on `address` in the Prometheus text format.
+pub async fn serve<F, Fut>(address: SocketAddr, render: F) -> anyhow::Result<()>
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = String> + Send,
+{
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", address, e))?;
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let render = render.clone();
+            tokio::spawn(async move {
+                let _ = tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, render)).await;
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn respond<F, Fut>(mut stream: TcpStream, render: F) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = String>,
+{
+    // only the request line matters, headers and bodies are ignored
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render().await),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub fn render(snapshot: &Snapshot) -> String {
+    let mut metrics = Metrics::default();
+    let active = snapshot.status.active.as_deref();
+
+    metrics.family("proxswap_active_configuration", "gauge", "1 for the active configuration, 0 for the others");
+    for name in snapshot.configurations.iter() {
+        metrics.sample("proxswap_active_configuration", &[("configuration", name)], (active == Some(name)) as u64);
+    }
+
+    if let Some(active) = active {
+        metrics.family("proxswap_proxy_up", "gauge", "Whether the proxy accepted a connection in the last check");
+        for proxy in snapshot.status.proxies.iter() {
+            let url = format!("{}:{}", proxy.url, proxy.port);
+            let labels = [("configuration", active), ("proxy", url.as_str())];
+            metrics.sample("proxswap_proxy_up", &labels, proxy.is_healthy() as u64);
+        }
+
+        metrics.family("proxswap_proxy_latency_seconds", "gauge", "Time it took to connect to the proxy in the last check");
+        for proxy in snapshot.status.proxies.iter() {
+            let Some(latency) = proxy.latency_ms else {
+                continue;
+            };
+            let url = format!("{}:{}", proxy.url, proxy.port);
+            metrics.sample(
+                "proxswap_proxy_latency_seconds",
+                &[("configuration", active), ("proxy", &url)],
+                latency as f64 / 1000.0,
+            );
+        }
+    }
+
+    if let Some(statistics) = snapshot.statistics {
+        let families = [
+            ("proxswap_connections_total", "Redirected connections that finished"),
+            ("proxswap_connection_errors_total", "Redirected connections that ended without data coming back"),
+        ];
+        for (name, help) in families {
+            metrics.family(name, "counter", help);
+            for configuration in snapshot.totals.iter() {
+                for (proxy, traffic) in configuration.proxies.iter() {
+                    let value = match name {
+                        "proxswap_connections_total" => traffic.connections,
+                        _ => traffic.errors,
+                    };
+                    metrics.sample(name, &[("configuration", &configuration.name), ("proxy", proxy)], value);
+                }
+            }
+        }
+
+        metrics.family("proxswap_bytes_total", "counter", "Bytes sent (up) and received (down) through the proxy");
+        for configuration in snapshot.totals.iter() {
+            for (proxy, traffic) in configuration.proxies.iter() {
+                for (direction, value) in [("up", traffic.bytes_up), ("down", traffic.bytes_down)] {
+                    metrics.sample(
+                        "proxswap_bytes_total",
+                        &[("configuration", &configuration.name), ("proxy", proxy), ("direction", direction)],
+                        value,
+                    );
+                }
+            }
+        }
+
+        if let Some(active) = active {
+            // (connections, bytes up, bytes down)
+            let mut open: BTreeMap<&str, (u64, u64, u64)> = BTreeMap::new();
+            for connection in statistics.connections.iter() {
+                let totals = open.entry(&connection.proxy).or_default();
+                totals.0 += 1;
+                totals.1 += connection.bytes_up;
+                totals.2 += connection.bytes_down;
+            }
+
+            metrics.family("proxswap_open_connections", "gauge", "Connections open through the active configuration");
+            for (proxy, (count, _, _)) in open.iter() {
+                metrics.sample("proxswap_open_connections", &[("configuration", active), ("proxy", proxy)], count);
+            }
+
+            metrics.family("proxswap_open_bytes", "gauge", "Bytes sent (up) and received (down) by the open connections so far");
+            for (proxy, (_, up, down)) in open.iter() {
+                for (direction, value) in [("up", up), ("down", down)] {
+                    metrics.sample(
+                        "proxswap_open_bytes",
+                        &[("configuration", active), ("proxy", proxy), ("direction", direction)],
+                        value,
+                    );
+                }
+            }
+        }
+    }
+
+    let counters = [
+        (
+            "proxswap_activation_failures_total",
+            "Activations that failed since proxswapd started",
+            &snapshot.counters.activation_failures,
+        ),
+        (
+            "proxswap_redirector_restarts_total",
+            "Times proxswapd (re)started redsocks for the configuration",
+            &snapshot.counters.redirector_restarts,
+        ),
+    ];
+    for (name, help, values) in counters {
+        metrics.family(name, "counter", help);
+        for (configuration, value) in values.iter() {
+            metrics.sample(name, &[("configuration", configuration)], *value);
+        }
+    }
+
+    metrics.0
+}
+
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = writeln!(self.0, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+// label values escape backslashes, quotes and line breaks
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::{LiveConnection, ProxyStatus, Traffic};
+
+    fn render_with(status: &Status, statistics: Option<&Statistics>, totals: &[ConfigurationTraffic]) -> String {
+        let configurations = vec!["home".to_string(), "office".to_string()];
+        let mut counters = Counters::default();
+        counters.activation_failures.insert("home".to_string(), 2);
+        counters.redirector_restarts.insert("office".to_string(), 3);
+
+        render(&Snapshot {
+            configurations: &configurations,
+            status,
+            statistics,
+            totals,
+            counters: &counters,
+        })
+    }
+
+    #[test]
+    fn nothing_active() {
+        let text = render_with(&Status::default(), None, &[]);
+
+        assert_eq!(
+            text,
+            "# HELP proxswap_active_configuration 1 for the active configuration, 0 for the others\n\
+             # TYPE proxswap_active_configuration gauge\n\
+             proxswap_active_configuration{configuration=\"home\"} 0\n\
+             proxswap_active_configuration{configuration=\"office\"} 0\n\
+             # HELP proxswap_activation_failures_total Activations that failed since proxswapd started\n\
+             # TYPE proxswap_activation_failures_total counter\n\
+             proxswap_activation_failures_total{configuration=\"home\"} 2\n\
+             # HELP proxswap_redirector_restarts_total Times proxswapd (re)started redsocks for the configuration\n\
+             # TYPE proxswap_redirector_restarts_total counter\n\
+             proxswap_redirector_restarts_total{configuration=\"office\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn active_configuration_with_traffic() {
+        let status = Status {
+            active: Some("office".to_string()),
+            proxies: vec![
+                ProxyStatus {
+                    url: "10.0.0.1".to_string(),
+                    port: 1080,
+                    latency_ms: Some(25),
+                    error: None,
+                },
+                ProxyStatus {
+                    url: "10.0.0.2".to_string(),
+                    port: 3128,
+                    latency_ms: None,
+                    error: Some("refused".to_string()),
+                },
+            ],
+        };
+        let traffic = Traffic {
+            connections: 4,
+            errors: 1,
+            bytes_up: 100,
+            bytes_down: 2000,
+        };
+        let totals = vec![ConfigurationTraffic {
+            name: "office".to_string(),
+            traffic,
+            proxies: BTreeMap::from([("10.0.0.1:1080".to_string(), traffic)]),
+            destinations: BTreeMap::new(),
+        }];
+        let statistics = Statistics {
+            connections: vec![LiveConnection {
+                destination: "1.1.1.1:443".to_string(),
+                proxy: "10.0.0.1:1080".to_string(),
+                age_secs: 5,
+                bytes_up: 10,
+                bytes_down: 20,
+            }],
+            ..Default::default()
+        };
+
+        let text = render_with(&status, Some(&statistics), &totals);
+        let samples: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+
+        assert_eq!(
+            samples,
+            [
+                "proxswap_active_configuration{configuration=\"home\"} 0",
+                "proxswap_active_configuration{configuration=\"office\"} 1",
+                "proxswap_proxy_up{configuration=\"office\",proxy=\"10.0.0.1:1080\"} 1",
+                "proxswap_proxy_up{configuration=\"office\",proxy=\"10.0.0.2:3128\"} 0",
+                "proxswap_proxy_latency_seconds{configuration=\"office\",proxy=\"10.0.0.1:1080\"} 0.025",
+                "proxswap_connections_total{configuration=\"office\",proxy=\"10.0.0.1:1080\"} 4",
+                "proxswap_connection_errors_total{configuration=\"office\",proxy=\"10.0.0.1:1080\"} 1",
+                "proxswap_bytes_total{configuration=\"office\",proxy=\"10.0.0.1:1080\",direction=\"up\"} 100",
+                "proxswap_bytes_total{configuration=\"office\",proxy=\"10.0.0.1:1080\",direction=\"down\"} 2000",
+                "proxswap_open_connections{configuration=\"office\",proxy=\"10.0.0.1:1080\"} 1",
+                "proxswap_open_bytes{configuration=\"office\",proxy=\"10.0.0.1:1080\",direction=\"up\"} 10",
+                "proxswap_open_bytes{configuration=\"office\",proxy=\"10.0.0.1:1080\",direction=\"down\"} 20",
+                "proxswap_activation_failures_total{configuration=\"home\"} 2",
+                "proxswap_redirector_restarts_total{configuration=\"office\"} 3",
+            ]
+        );
+        // every family is declared once, before its samples
+        assert_eq!(text.matches("# TYPE proxswap_bytes_total counter\n").count(), 1);
+        assert!(text.find("# TYPE proxswap_open_bytes gauge").unwrap() < text.find("proxswap_open_bytes{").unwrap());
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape(r#"a\b"c"#), r#"a\\b\"c"#);
+        assert_eq!(escape("one\ntwo"), "one\\ntwo");
+
+        let mut metrics = Metrics::default();
+        metrics.sample("m", &[("configuration", "say \"hi\"\n"), ("proxy", "C:\\")], 1);
+        assert_eq!(metrics.0, "m{configuration=\"say \\\"hi\\\"\\n\",proxy=\"C:\\\\\"} 1\n");
+    }
+}
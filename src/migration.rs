@@ -0,0 +1,204 @@
+use serde_json::{json, Value};
+use crate::configuration::write_atomic;
+use std::fs::{copy, read_to_string};
+use std::path::Path;
+
+type Migration = fn(&mut Value) -> anyhow::Result<()>;
+
+// MIGRATIONS[n] upgrades a version n document to version n + 1
+const MIGRATIONS: [Migration; 1] = [v0_to_v1];
+
+/// Version written by this build, see `schema/configuration.schema.json`.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Files from before the `version` field existed count as version 0.
+pub fn schema_version(document: &Value) -> anyhow::Result<u32> {
+    match document.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow::anyhow!("\"version\" must be a non-negative integer")),
+    }
+}
+
+/// Upgrades `document` to `SCHEMA_VERSION`, returning the version it started at.
+pub fn migrate(document: &mut Value) -> anyhow::Result<u32> {
+    let version = schema_version(document)?;
+
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "schema version {} is newer than the supported version {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(document)
+            .map_err(|e| anyhow::anyhow!("Failed to migrate from version {}: {}", from, e))?;
+        document["version"] = json!(from + 1);
+    }
+
+    Ok(version)
+}
+
+/// Upgrades a configuration file in place, keeping the original next to it as `<file>.v<N>.bak`.
+pub fn migrate_file(path: &Path) -> anyhow::Result<()> {
+    let mut document: Value = serde_json::from_str(&read_to_string(path)?)?;
+    let version = migrate(&mut document)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let backup = format!("{}.v{}.bak", path.display(), version);
+    copy(path, &backup)
+        .map_err(|e| anyhow::anyhow!("Failed to back up {} to {}: {}", path.display(), backup, e))?;
+
+    write_atomic(path, serde_json::to_string_pretty(&document)?.as_bytes())
+}
+
+// v1 added the version field and settled on canonical spellings for the typed fields
+fn v0_to_v1(document: &mut Value) -> anyhow::Result<()> {
+    let config = document
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("configuration must be a JSON object"))?;
+
+    if let Some(proxies) = config.get_mut("proxies").and_then(Value::as_array_mut) {
+        for proxy in proxies.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(proxy_type) = proxy.get("proxy_type").and_then(Value::as_str) {
+                let proxy_type = match proxy_type.to_lowercase().as_str() {
+                    "http" => "http-connect".to_string(),
+                    other => other.to_string(),
+                };
+                proxy.insert("proxy_type".to_string(), json!(proxy_type));
+            }
+
+            if let Some(port) = proxy.get("port").and_then(Value::as_str) {
+                let port: u16 = port.trim().parse()?;
+                proxy.insert("port".to_string(), json!(port));
+            }
+
+            proxy.entry("login").or_insert(json!(""));
+            proxy.entry("password").or_insert(json!(""));
+        }
+    }
+
+    if let Some(rules) = config.get_mut("rules").and_then(Value::as_array_mut) {
+        for rule in rules.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(dport) = rule.get("dport").and_then(Value::as_u64) {
+                rule.insert("dport".to_string(), json!(dport.to_string()));
+            }
+
+            if let Some(action) = rule.get("action").and_then(Value::as_str) {
+                let action = action.to_uppercase();
+                rule.insert("action".to_string(), json!(action));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v0() -> Value {
+        json!({
+            "name": "office",
+            "proxies": [{ "proxy_type": "HTTP", "url": "10.0.0.1", "port": "3128" }],
+            "rules": [{ "dport": 80, "to_port": 14888, "action": "redirect" }],
+            "comment": "kept"
+        })
+    }
+
+    // a directory of its own per test, removed again at the end
+    fn scratch(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxswap-migration-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn v0_documents_get_canonical_fields() {
+        let mut document = v0();
+        assert_eq!(migrate(&mut document).unwrap(), 0);
+        assert_eq!(
+            document,
+            json!({
+                "name": "office",
+                "proxies": [{ "proxy_type": "http-connect", "url": "10.0.0.1", "port": 3128, "login": "", "password": "" }],
+                "rules": [{ "dport": "80", "to_port": 14888, "action": "REDIRECT" }],
+                "comment": "kept",
+                "version": SCHEMA_VERSION
+            })
+        );
+
+        // migrating again changes nothing
+        let migrated = document.clone();
+        assert_eq!(migrate(&mut document).unwrap(), SCHEMA_VERSION);
+        assert_eq!(document, migrated);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(schema_version(&json!({})).unwrap(), 0);
+        assert_eq!(schema_version(&json!({ "version": 1 })).unwrap(), 1);
+        for invalid in [json!(-1), json!("1"), json!(1.5), json!(u64::MAX)] {
+            assert!(schema_version(&json!({ "version": invalid })).is_err(), "{} should be rejected", invalid);
+        }
+
+        let mut newer = json!({ "version": SCHEMA_VERSION + 1 });
+        assert!(migrate(&mut newer).unwrap_err().to_string().contains("is newer than"));
+    }
+
+    #[test]
+    fn failed_migrations_name_the_version() {
+        let mut document = v0();
+        document["proxies"][0]["port"] = json!("http");
+        let error = migrate(&mut document).unwrap_err().to_string();
+        assert!(error.starts_with("Failed to migrate from version 0"), "{}", error);
+    }
+
+    #[test]
+    fn files_are_backed_up_before_they_are_rewritten() {
+        let dir = scratch("backup");
+        let path = dir.join("office.json");
+        let original = serde_json::to_string(&v0()).unwrap();
+        std::fs::write(&path, &original).unwrap();
+
+        migrate_file(&path).unwrap();
+
+        assert_eq!(read_to_string(dir.join("office.json.v0.bak")).unwrap(), original);
+        let migrated: Value = serde_json::from_str(&read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(migrated["version"], json!(SCHEMA_VERSION));
+        assert_eq!(migrated["proxies"][0]["port"], json!(3128));
+
+        // a current file is left alone, without a backup
+        let current = read_to_string(&path).unwrap();
+        migrate_file(&path).unwrap();
+        assert_eq!(read_to_string(&path).unwrap(), current);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_files_are_left_alone() {
+        let dir = scratch("broken");
+        let path = dir.join("office.json");
+        let mut document = v0();
+        document["version"] = json!(SCHEMA_VERSION + 1);
+        let contents = serde_json::to_string(&document).unwrap();
+        std::fs::write(&path, &contents).unwrap();
+
+        assert!(migrate_file(&path).is_err());
+        assert_eq!(read_to_string(&path).unwrap(), contents);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
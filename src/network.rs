@@ -0,0 +1,387 @@
+use crate::configuration::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::process::Command;
+
+// linux/rtnetlink.h
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+
+/// Picks a configuration when every condition it sets holds. A rule without conditions always
+/// matches, which makes it a fallback at the end of the list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkRule {
+    /// MAC address of the default gateway, e.g. `aa:bb:cc:dd:ee:ff`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// Interface of the default route, e.g. `usb0` for a tethered phone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Matches when one of the local addresses is in it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subnet: Option<Ipv4Net>,
+    /// Configuration to activate, `null` for a direct connection without proxies
+    pub configuration: Option<String>,
+}
+
+impl NetworkRule {
+    pub fn matches(&self, network: &Network) -> bool {
+        let gateway_mac = self.gateway_mac.as_ref().is_none_or(|mac| {
+            network.gateway_mac.as_ref().is_some_and(|gateway_mac| gateway_mac.eq_ignore_ascii_case(mac))
+        });
+        let ssid = self.ssid.as_ref().is_none_or(|ssid| network.ssid.as_ref() == Some(ssid));
+        let interface = self
+            .interface
+            .as_ref()
+            .is_none_or(|interface| network.interface.as_ref() == Some(interface));
+        let subnet = self
+            .subnet
+            .is_none_or(|subnet| network.addresses.iter().any(|&addr| subnet.contains(addr)));
+
+        gateway_mac && ssid && interface && subnet
+    }
+
+    /// The configuration's name, or "direct".
+    pub fn target(&self) -> &str {
+        self.configuration.as_deref().unwrap_or("direct")
+    }
+}
+
+/// What is known about the network the machine is connected to.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub interface: Option<String>,
+    pub gateway: Option<Ipv4Addr>,
+    pub gateway_mac: Option<String>,
+    pub ssid: Option<String>,
+    /// IPv4 addresses of every interface except loopback
+    pub addresses: Vec<Ipv4Addr>,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "-".to_string();
+        let addresses: Vec<String> = self.addresses.iter().map(Ipv4Addr::to_string).collect();
+
+        writeln!(f, "interface:   {}", self.interface.as_ref().unwrap_or(&unknown))?;
+        writeln!(f, "gateway:     {}", self.gateway.map(|gateway| gateway.to_string()).unwrap_or(unknown.clone()))?;
+        writeln!(f, "gateway_mac: {}", self.gateway_mac.as_ref().unwrap_or(&unknown))?;
+        writeln!(f, "ssid:        {}", self.ssid.as_ref().unwrap_or(&unknown))?;
+        write!(f, "addresses:   {}", addresses.join(", "))
+    }
+}
+
+pub async fn current_network() -> Network {
+    let mut network = Network {
+        addresses: local_addresses(),
+        ..Default::default()
+    };
+
+    let Some((interface, gateway)) = default_route() else {
+        return network;
+    };
+
+    network.gateway_mac = gateway_mac(gateway, &interface);
+    if network.gateway_mac.is_none() {
+        // nothing has talked to the gateway yet, a datagram makes the kernel resolve its address
+        if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
+            let _ = socket.send_to(&[], (gateway, 9));
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            network.gateway_mac = gateway_mac(gateway, &interface);
+        }
+    }
+
+    network.ssid = ssid(&interface).await;
+    network.interface = Some(interface);
+    network.gateway = Some(gateway);
+
+    network
+}
+
+/// Interface and gateway of the IPv4 default route with the lowest metric.
+pub fn default_route() -> Option<(String, Ipv4Addr)> {
+    parse_default_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+fn parse_default_route(routes: &str) -> Option<(String, Ipv4Addr)> {
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Iface Destination Gateway Flags RefCnt Use Metric ..., addresses in host byte order
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+            if fields[1] != "00000000" || flags & libc::RTF_UP == 0 {
+                return None;
+            }
+
+            let gateway = Ipv4Addr::from(u32::from_str_radix(fields[2], 16).ok()?.to_ne_bytes());
+            let metric: u32 = fields.get(6)?.parse().ok()?;
+            Some((metric, fields[0].to_string(), gateway))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, interface, gateway)| (interface, gateway))
+}
+
+fn gateway_mac(gateway: Ipv4Addr, interface: &str) -> Option<String> {
+    let neighbours = std::fs::read_to_string("/proc/net/arp").ok()?;
+    let gateway = gateway.to_string();
+
+    // IP address, HW type, Flags, HW address, Mask, Device
+    neighbours.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.len() >= 6 && fields[0] == gateway && fields[5] == interface && fields[3] != "00:00:00:00:00:00")
+            .then(|| fields[3].to_lowercase())
+    })
+}
+
+// asks the wireless tools that are installed, wired interfaces have no SSID
+async fn ssid(interface: &str) -> Option<String> {
+    if let Ok(output) = Command::new("iwgetid").args(["-r", interface]).output().await {
+        let ssid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !ssid.is_empty() {
+            return Some(ssid);
+        }
+    }
+
+    let output = Command::new("nmcli")
+        .args(["-t", "-f", "ACTIVE,SSID,DEVICE", "device", "wifi"])
+        .output()
+        .await
+        .ok()?;
+
+    active_ssid(&String::from_utf8_lossy(&output.stdout), interface)
+}
+
+// nmcli -t prints ACTIVE:SSID:DEVICE, with ':' and '\' in the SSID escaped as \: and \\
+fn active_ssid(output: &str, interface: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let fields = split_escaped(line);
+        (fields.len() == 3 && fields[0] == "yes" && fields[2] == interface).then(|| fields[1].clone())
+    })
+}
+
+fn split_escaped(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => fields.last_mut().unwrap().extend(chars.next()),
+            ':' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn local_addresses() -> Vec<Ipv4Addr> {
+    let mut addresses = Vec::new();
+
+    unsafe {
+        let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut interfaces) != 0 {
+            return addresses;
+        }
+
+        let mut interface = interfaces;
+        while let Some(current) = interface.as_ref() {
+            let address = current.ifa_addr;
+            if !address.is_null() && (*address).sa_family as i32 == libc::AF_INET {
+                let address = &*(address as *const libc::sockaddr_in);
+                let addr = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+                if !addr.is_loopback() && CStr::from_ptr(current.ifa_name).to_bytes() != b"lo" {
+                    addresses.push(addr);
+                }
+            }
+            interface = current.ifa_next;
+        }
+
+        libc::freeifaddrs(interfaces);
+    }
+
+    addresses
+}
+
+/// Route, address and link notifications from the kernel.
+pub struct RouteWatcher {
+    socket: AsyncFd<OwnedFd>,
+}
+
+impl RouteWatcher {
+    pub fn new() -> io::Result<RouteWatcher> {
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_ROUTE,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+
+            let mut address: libc::sockaddr_nl = std::mem::zeroed();
+            address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            address.nl_groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE;
+            if libc::bind(
+                fd,
+                (&address as *const libc::sockaddr_nl).cast(),
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            // the OwnedFd keeps the descriptor open for as long as the AsyncFd exists
+            Ok(RouteWatcher {
+                socket: AsyncFd::register_with_interest(socket, Interest::READABLE)?,
+            })
+        }
+    }
+
+    /// Waits for the next notification.
+    pub async fn changed(&mut self) -> io::Result<()> {
+        loop {
+            let mut guard = self.socket.readable().await?;
+            match guard.try_io(|socket| receive(socket.get_ref())) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Throws away the notifications that queued up, e.g. while a connection was being set up.
+    pub fn drain(&mut self) {
+        while receive(self.socket.get_ref()).is_ok() {}
+    }
+}
+
+fn receive(socket: &OwnedFd) -> io::Result<()> {
+    let mut buffer = [0u8; 8192];
+    let received = unsafe { libc::recv(socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+
+    if received < 0 {
+        let e = io::Error::last_os_error();
+        // notifications were dropped because they came faster than they were read, still a change
+        if e.raw_os_error() != Some(libc::ENOBUFS) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn active_ssid_of_the_interface() {
+        let output = "no:Neighbours:wlan0\nyes:Office:wlan1\nyes:Home:wlan0\n";
+        assert_eq!(active_ssid(output, "wlan0").as_deref(), Some("Home"));
+        assert_eq!(active_ssid(output, "wlan1").as_deref(), Some("Office"));
+        assert_eq!(active_ssid(output, "eth0"), None);
+        assert_eq!(active_ssid("", "wlan0"), None);
+    }
+
+    #[test]
+    fn escaped_colons_and_backslashes() {
+        assert_eq!(active_ssid("yes:Cafe\\:2G:wlan0\n", "wlan0").as_deref(), Some("Cafe:2G"));
+        assert_eq!(active_ssid("yes:a\\\\b:wlan0\n", "wlan0").as_deref(), Some("a\\b"));
+        // a backslash right before the separator
+        assert_eq!(active_ssid("yes:end\\\\:wlan0\n", "wlan0").as_deref(), Some("end\\"));
+        assert_eq!(active_ssid("yes:\\:\\:\\::wlan0\n", "wlan0").as_deref(), Some(":::"));
+        // an unescaped colon makes it another field
+        assert_eq!(active_ssid("yes:a:b:wlan0\n", "wlan0"), None);
+    }
+
+    fn network() -> Network {
+        Network {
+            interface: Some("wlan0".to_string()),
+            gateway: Some(Ipv4Addr::new(192, 168, 1, 1)),
+            gateway_mac: Some("aa:bb:cc:dd:ee:ff".to_string()),
+            ssid: Some("Office".to_string()),
+            addresses: vec![Ipv4Addr::new(192, 168, 1, 23), Ipv4Addr::new(10, 8, 0, 2)],
+        }
+    }
+
+    fn rule(rule: serde_json::Value) -> NetworkRule {
+        serde_json::from_value(rule).unwrap()
+    }
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        let network = network();
+        assert!(rule(json!({ "configuration": "fallback" })).matches(&network));
+        assert!(rule(json!({ "gateway_mac": "AA:BB:CC:DD:EE:FF", "configuration": "office" })).matches(&network));
+        assert!(rule(json!({ "ssid": "Office", "interface": "wlan0", "configuration": "office" })).matches(&network));
+        assert!(!rule(json!({ "ssid": "office", "configuration": "office" })).matches(&network));
+        assert!(!rule(json!({ "ssid": "Office", "interface": "usb0", "configuration": "phone" })).matches(&network));
+        assert!(rule(json!({ "subnet": "10.8.0.0/24", "configuration": "vpn" })).matches(&network));
+        assert!(!rule(json!({ "subnet": "10.9.0.0/16", "configuration": "vpn" })).matches(&network));
+    }
+
+    #[test]
+    fn unknown_facts_only_match_rules_without_them() {
+        let offline = Network::default();
+        assert!(!rule(json!({ "gateway_mac": "aa:bb:cc:dd:ee:ff", "configuration": "x" })).matches(&offline));
+        assert!(!rule(json!({ "ssid": "Office", "configuration": "x" })).matches(&offline));
+        assert!(!rule(json!({ "subnet": "0.0.0.0/0", "configuration": "x" })).matches(&offline));
+
+        let direct = rule(json!({ "configuration": null }));
+        assert!(direct.matches(&offline));
+        assert_eq!(direct.target(), "direct");
+    }
+
+    #[test]
+    fn subnets() {
+        let net: Ipv4Net = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(net.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert!(net.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(net.contains(Ipv4Addr::new(192, 168, 1, 255)));
+        assert!(!net.contains(Ipv4Addr::new(192, 168, 2, 1)));
+
+        let host: Ipv4Net = "10.0.0.1".parse().unwrap();
+        assert_eq!(host.netmask(), Ipv4Addr::new(255, 255, 255, 255));
+        assert!(host.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!host.contains(Ipv4Addr::new(10, 0, 0, 2)));
+
+        let everything: Ipv4Net = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(everything.netmask(), Ipv4Addr::new(0, 0, 0, 0));
+        assert!(everything.contains(Ipv4Addr::new(8, 8, 8, 8)));
+
+        let odd: Ipv4Net = "172.16.0.0/12".parse().unwrap();
+        assert_eq!(odd.netmask(), Ipv4Addr::new(255, 240, 0, 0));
+        assert!(odd.contains(Ipv4Addr::new(172, 31, 255, 255)));
+        assert!(!odd.contains(Ipv4Addr::new(172, 32, 0, 0)));
+    }
+
+    // /proc/net/route writes addresses in host byte order
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn default_route_with_the_lowest_metric() {
+        let routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+usb0\t00000000\t012BA8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t00000000\t0100000A\t0002\t0\t0\t50\t00000000\t0\t0\t0
+wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+";
+        // eth0's route isn't up, the subnet route isn't a default route
+        assert_eq!(parse_default_route(routes), Some(("usb0".to_string(), Ipv4Addr::new(192, 168, 43, 1))));
+        let wlan = routes.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert_eq!(parse_default_route(&wlan), Some(("wlan0".to_string(), Ipv4Addr::new(192, 168, 1, 1))));
+        assert_eq!(parse_default_route("Iface\tDestination\n"), None);
+        assert_eq!(parse_default_route("Iface\nwlan0\t00000000\n"), None);
+    }
+}
@@ -0,0 +1,630 @@
+use crate::configuration::{IptablesRule, PortRange, RuleAction};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// everything proxswap adds lives in this table, so removing it never touches other rules
+const TABLE: &str = "proxswap";
+const CHAIN: &str = "output";
+
+// linux/netlink.h
+const NETLINK_NETFILTER: i32 = 12;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLMSG_ERROR: u16 = 0x2;
+const NLA_F_NESTED: u16 = 0x8000;
+
+// linux/netfilter/nfnetlink.h and linux/netfilter/nf_tables.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFPROTO_IPV4: u8 = 2;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_REDIR_REG_PROTO_MIN: u16 = 1;
+
+const NF_INET_LOCAL_OUT: u32 = 3;
+const NF_IP_PRI_NAT_DST: i32 = -100;
+const NF_ACCEPT: u32 = 1;
+const NFT_RETURN: i32 = -5;
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_LTE: u32 = 3;
+const NFT_CMP_GTE: u32 = 5;
+const IPPROTO_TCP: u8 = 6;
+
+/// Replaces the `ip proxswap` table with one holding `rules`, in a single transaction.
+///
+/// Like the iptables rules, the first rule that matches a connection decides. Rules with a
+/// list of ports become one nftables rule per range.
+pub fn replace_rules(rules: &[IptablesRule]) -> io::Result<()> {
+    replace_batch(rules).send()
+}
+
+/// Removes the `ip proxswap` table and with it every rule proxswap added.
+pub fn delete_rules() -> io::Result<()> {
+    delete_batch().send()
+}
+
+fn replace_batch(rules: &[IptablesRule]) -> Batch {
+    let mut batch = Batch::new();
+
+    // adding the table first makes deleting it work whether it exists or not
+    batch.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, table());
+    batch.push(NFT_MSG_DELTABLE, 0, table());
+    batch.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, table());
+    batch.push(NFT_MSG_NEWCHAIN, NLM_F_CREATE, chain());
+
+    for rule in rules.iter() {
+        for range in rule.dport.ranges() {
+            batch.push(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, nft_rule(rule, range));
+        }
+    }
+
+    batch
+}
+
+fn delete_batch() -> Batch {
+    let mut batch = Batch::new();
+    batch.push(NFT_MSG_NEWTABLE, NLM_F_CREATE, table());
+    batch.push(NFT_MSG_DELTABLE, 0, table());
+    batch
+}
+
+fn table() -> Attributes {
+    Attributes::new().string(NFTA_TABLE_NAME, TABLE)
+}
+
+fn chain() -> Attributes {
+    Attributes::new()
+        .string(NFTA_CHAIN_TABLE, TABLE)
+        .string(NFTA_CHAIN_NAME, CHAIN)
+        .nested(
+            NFTA_CHAIN_HOOK,
+            Attributes::new()
+                .u32(NFTA_HOOK_HOOKNUM, NF_INET_LOCAL_OUT)
+                .u32(NFTA_HOOK_PRIORITY, NF_IP_PRI_NAT_DST as u32),
+        )
+        .u32(NFTA_CHAIN_POLICY, NF_ACCEPT)
+        .string(NFTA_CHAIN_TYPE, "nat")
+}
+
+// tcp [ip daddr <destination>] [tcp dport <range>] redirect to :<to_port> | return
+fn nft_rule(rule: &IptablesRule, range: &PortRange) -> Attributes {
+    let mut expressions = Attributes::new()
+        .expression("meta", Attributes::new().u32(NFTA_META_KEY, NFT_META_L4PROTO).u32(NFTA_META_DREG, NFT_REG_1))
+        .expression("cmp", cmp(NFT_CMP_EQ, &[IPPROTO_TCP]));
+
+    if let Some(destination) = rule.destination {
+        let mask = u32::from(destination.netmask());
+        let network = u32::from(destination.addr) & mask;
+
+        expressions = expressions
+            .expression("payload", payload(NFT_PAYLOAD_NETWORK_HEADER, 16, 4))
+            .expression(
+                "bitwise",
+                Attributes::new()
+                    .u32(NFTA_BITWISE_SREG, NFT_REG_1)
+                    .u32(NFTA_BITWISE_DREG, NFT_REG_1)
+                    .u32(NFTA_BITWISE_LEN, 4)
+                    .nested(NFTA_BITWISE_MASK, Attributes::new().bytes(NFTA_DATA_VALUE, &mask.to_be_bytes()))
+                    .nested(NFTA_BITWISE_XOR, Attributes::new().bytes(NFTA_DATA_VALUE, &[0; 4])),
+            )
+            .expression("cmp", cmp(NFT_CMP_EQ, &network.to_be_bytes()));
+    }
+
+    if (range.start, range.end) != (1, u16::MAX) {
+        expressions = expressions.expression("payload", payload(NFT_PAYLOAD_TRANSPORT_HEADER, 2, 2));
+
+        if range.start == range.end {
+            expressions = expressions.expression("cmp", cmp(NFT_CMP_EQ, &range.start.to_be_bytes()));
+        } else {
+            expressions = expressions
+                .expression("cmp", cmp(NFT_CMP_GTE, &range.start.to_be_bytes()))
+                .expression("cmp", cmp(NFT_CMP_LTE, &range.end.to_be_bytes()));
+        }
+    }
+
+    expressions = match rule.action {
+        RuleAction::Redirect => expressions
+            .expression(
+                "immediate",
+                Attributes::new()
+                    .u32(NFTA_IMMEDIATE_DREG, NFT_REG_1)
+                    .nested(NFTA_IMMEDIATE_DATA, Attributes::new().bytes(NFTA_DATA_VALUE, &rule.to_port.to_be_bytes())),
+            )
+            .expression("redir", Attributes::new().u32(NFTA_REDIR_REG_PROTO_MIN, NFT_REG_1)),
+        RuleAction::Return => expressions.expression(
+            "immediate",
+            Attributes::new().u32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT).nested(
+                NFTA_IMMEDIATE_DATA,
+                Attributes::new().nested(
+                    NFTA_DATA_VERDICT,
+                    Attributes::new().u32(NFTA_VERDICT_CODE, NFT_RETURN as u32),
+                ),
+            ),
+        ),
+    };
+
+    Attributes::new()
+        .string(NFTA_RULE_TABLE, TABLE)
+        .string(NFTA_RULE_CHAIN, CHAIN)
+        .nested(NFTA_RULE_EXPRESSIONS, expressions)
+}
+
+fn payload(base: u32, offset: u32, len: u32) -> Attributes {
+    Attributes::new()
+        .u32(NFTA_PAYLOAD_DREG, NFT_REG_1)
+        .u32(NFTA_PAYLOAD_BASE, base)
+        .u32(NFTA_PAYLOAD_OFFSET, offset)
+        .u32(NFTA_PAYLOAD_LEN, len)
+}
+
+fn cmp(op: u32, value: &[u8]) -> Attributes {
+    Attributes::new()
+        .u32(NFTA_CMP_SREG, NFT_REG_1)
+        .u32(NFTA_CMP_OP, op)
+        .nested(NFTA_CMP_DATA, Attributes::new().bytes(NFTA_DATA_VALUE, value))
+}
+
+/// Netlink attributes, integers are sent in network byte order as nf_tables expects.
+struct Attributes(Vec<u8>);
+
+impl Attributes {
+    fn new() -> Self {
+        Attributes(Vec::new())
+    }
+
+    fn bytes(mut self, kind: u16, value: &[u8]) -> Self {
+        self.0.extend(((value.len() + 4) as u16).to_ne_bytes());
+        self.0.extend(kind.to_ne_bytes());
+        self.0.extend(value);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+        self
+    }
+
+    fn string(self, kind: u16, value: &str) -> Self {
+        let mut terminated = value.as_bytes().to_vec();
+        terminated.push(0);
+        self.bytes(kind, &terminated)
+    }
+
+    fn u32(self, kind: u16, value: u32) -> Self {
+        self.bytes(kind, &value.to_be_bytes())
+    }
+
+    fn nested(self, kind: u16, attributes: Attributes) -> Self {
+        self.bytes(kind | NLA_F_NESTED, &attributes.0)
+    }
+
+    fn expression(self, name: &str, data: Attributes) -> Self {
+        self.nested(
+            NFTA_LIST_ELEM,
+            Attributes::new().string(NFTA_EXPR_NAME, name).nested(NFTA_EXPR_DATA, data),
+        )
+    }
+}
+
+/// nf_tables only accepts changes as a transaction between a batch begin and end message.
+struct Batch {
+    buffer: Vec<u8>,
+    seq: u32,
+}
+
+impl Batch {
+    fn new() -> Self {
+        let mut batch = Batch {
+            buffer: Vec::new(),
+            seq: 0,
+        };
+        batch.message(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, 0, NFNL_SUBSYS_NFTABLES, &[]);
+        batch
+    }
+
+    fn push(&mut self, message: u16, flags: u16, attributes: Attributes) {
+        self.message(
+            (NFNL_SUBSYS_NFTABLES << 8) | message,
+            NLM_F_REQUEST | NLM_F_ACK | flags,
+            NFPROTO_IPV4,
+            0,
+            &attributes.0,
+        );
+    }
+
+    fn message(&mut self, kind: u16, flags: u16, family: u8, resource: u16, payload: &[u8]) {
+        self.seq += 1;
+        let len = 16 + 4 + payload.len();
+
+        // struct nlmsghdr followed by struct nfgenmsg
+        self.buffer.extend((len as u32).to_ne_bytes());
+        self.buffer.extend(kind.to_ne_bytes());
+        self.buffer.extend(flags.to_ne_bytes());
+        self.buffer.extend(self.seq.to_ne_bytes());
+        self.buffer.extend(0u32.to_ne_bytes());
+        self.buffer.extend([family, 0]);
+        self.buffer.extend(resource.to_be_bytes());
+        self.buffer.extend(payload);
+    }
+
+    // ends the batch, returns the sequence number of its last change
+    fn finish(&mut self) -> u32 {
+        let last_seq = self.seq;
+        self.message(NFNL_MSG_BATCH_END, NLM_F_REQUEST, 0, NFNL_SUBSYS_NFTABLES, &[]);
+        last_seq
+    }
+
+    fn send(mut self) -> io::Result<()> {
+        let last_seq = self.finish();
+
+        let socket = open_socket()?;
+        let sent = unsafe {
+            libc::send(socket.as_raw_fd(), self.buffer.as_ptr().cast(), self.buffer.len(), 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // every change asked for an ack, the kernel answers them in order
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let received = unsafe {
+                libc::recv(socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0)
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut messages = &buffer[..received as usize];
+            while messages.len() >= 20 {
+                let len = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+
+                if kind == NLMSG_ERROR {
+                    let error = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+                    if error != 0 {
+                        return Err(io::Error::from_raw_os_error(-error));
+                    }
+                    if seq == last_seq {
+                        return Ok(());
+                    }
+                }
+
+                if len < 16 || len > messages.len() {
+                    break;
+                }
+                messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+            }
+        }
+    }
+}
+
+fn open_socket() -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_NETFILTER);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+
+        let mut address: libc::sockaddr_nl = std::mem::zeroed();
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        if libc::bind(
+            fd,
+            (&address as *const libc::sockaddr_nl).cast(),
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        // don't hang the caller if the kernel never answers
+        let timeout = libc::timeval { tv_sec: 5, tv_usec: 0 };
+        if libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            (&timeout as *const libc::timeval).cast(),
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+// golden messages, written out from linux/netlink.h and linux/netfilter/nf_tables.h; headers and
+// attribute headers are in host byte order, so these are for little endian
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // hex words, one attribute per line, `//` starts a comment
+    fn hex(text: &str) -> Vec<u8> {
+        let digits: String = text
+            .lines()
+            .flat_map(|line| line.split("//").next().unwrap_or_default().split_whitespace())
+            .collect();
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn rule(rule: serde_json::Value) -> IptablesRule {
+        serde_json::from_value(rule).unwrap()
+    }
+
+    fn finished(mut batch: Batch) -> Vec<u8> {
+        batch.finish();
+        batch.buffer
+    }
+
+    #[test]
+    fn return_for_one_port() {
+        let rule = rule(json!({ "dport": 22, "to_port": 0, "action": "RETURN" }));
+
+        // tcp dport 22 return
+        let expected = hex("
+            0d000100 70726f78 73776170 00000000  // NFTA_RULE_TABLE \"proxswap\"
+            0b000200 6f757470 75740000  // NFTA_RULE_CHAIN \"output\"
+            e4000480  // NFTA_RULE_EXPRESSIONS
+                24000180  // NFTA_LIST_ELEM
+                    09000100 6d657461 00000000  // NFTA_EXPR_NAME \"meta\"
+                    14000280  // NFTA_EXPR_DATA
+                        08000200 00000010  // NFTA_META_KEY L4PROTO 16
+                        08000100 00000001  // NFTA_META_DREG 1
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000000  // NFTA_CMP_OP EQ 0
+                        0c000380  // NFTA_CMP_DATA
+                            05000100 06000000  // NFTA_DATA_VALUE
+                34000180  // NFTA_LIST_ELEM
+                    0c000100 7061796c 6f616400  // NFTA_EXPR_NAME \"payload\"
+                    24000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_PAYLOAD_DREG 1
+                        08000200 00000002  // NFTA_PAYLOAD_BASE 2
+                        08000300 00000002  // NFTA_PAYLOAD_OFFSET 2
+                        08000400 00000002  // NFTA_PAYLOAD_LEN 2
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000000  // NFTA_CMP_OP EQ 0
+                        0c000380  // NFTA_CMP_DATA
+                            06000100 00160000  // NFTA_DATA_VALUE
+                30000180  // NFTA_LIST_ELEM
+                    0e000100 696d6d65 64696174 65000000  // NFTA_EXPR_NAME \"immediate\"
+                    1c000280  // NFTA_EXPR_DATA
+                        08000100 00000000  // NFTA_IMMEDIATE_DREG verdict 0
+                        10000280  // NFTA_IMMEDIATE_DATA
+                            0c000280  // NFTA_DATA_VERDICT
+                                08000100 fffffffb  // NFTA_VERDICT_CODE RETURN -5
+        ");
+        assert_eq!(nft_rule(&rule, &rule.dport.ranges()[0]).0, expected);
+    }
+
+    #[test]
+    fn redirect_for_a_range_and_subnet() {
+        let rule = rule(json!({ "dport": "8000:8080", "destination": "10.1.2.3/8", "to_port": 12345, "action": "REDIRECT" }));
+
+        // tcp ip daddr 10.0.0.0/8 tcp dport 8000-8080 redirect to :12345
+        let expected = hex("
+            0d000100 70726f78 73776170 00000000  // NFTA_RULE_TABLE \"proxswap\"
+            0b000200 6f757470 75740000  // NFTA_RULE_CHAIN \"output\"
+            cc010480  // NFTA_RULE_EXPRESSIONS
+                24000180  // NFTA_LIST_ELEM
+                    09000100 6d657461 00000000  // NFTA_EXPR_NAME \"meta\"
+                    14000280  // NFTA_EXPR_DATA
+                        08000200 00000010  // NFTA_META_KEY L4PROTO 16
+                        08000100 00000001  // NFTA_META_DREG 1
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000000  // NFTA_CMP_OP EQ 0
+                        0c000380  // NFTA_CMP_DATA
+                            05000100 06000000  // NFTA_DATA_VALUE
+                34000180  // NFTA_LIST_ELEM
+                    0c000100 7061796c 6f616400  // NFTA_EXPR_NAME \"payload\"
+                    24000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_PAYLOAD_DREG 1
+                        08000200 00000001  // NFTA_PAYLOAD_BASE 1
+                        08000300 00000010  // NFTA_PAYLOAD_OFFSET 16
+                        08000400 00000004  // NFTA_PAYLOAD_LEN 4
+                44000180  // NFTA_LIST_ELEM
+                    0c000100 62697477 69736500  // NFTA_EXPR_NAME \"bitwise\"
+                    34000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_BITWISE_SREG 1
+                        08000200 00000001  // NFTA_BITWISE_DREG 1
+                        08000300 00000004  // NFTA_BITWISE_LEN 4
+                        0c000480  // NFTA_BITWISE_MASK
+                            08000100 ff000000  // NFTA_DATA_VALUE
+                        0c000580  // NFTA_BITWISE_XOR
+                            08000100 00000000  // NFTA_DATA_VALUE
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000000  // NFTA_CMP_OP EQ 0
+                        0c000380  // NFTA_CMP_DATA
+                            08000100 0a000000  // NFTA_DATA_VALUE
+                34000180  // NFTA_LIST_ELEM
+                    0c000100 7061796c 6f616400  // NFTA_EXPR_NAME \"payload\"
+                    24000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_PAYLOAD_DREG 1
+                        08000200 00000002  // NFTA_PAYLOAD_BASE 2
+                        08000300 00000002  // NFTA_PAYLOAD_OFFSET 2
+                        08000400 00000002  // NFTA_PAYLOAD_LEN 2
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000005  // NFTA_CMP_OP GTE 5
+                        0c000380  // NFTA_CMP_DATA
+                            06000100 1f400000  // NFTA_DATA_VALUE
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000003  // NFTA_CMP_OP LTE 3
+                        0c000380  // NFTA_CMP_DATA
+                            06000100 1f900000  // NFTA_DATA_VALUE
+                2c000180  // NFTA_LIST_ELEM
+                    0e000100 696d6d65 64696174 65000000  // NFTA_EXPR_NAME \"immediate\"
+                    18000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_IMMEDIATE_DREG 1
+                        0c000280  // NFTA_IMMEDIATE_DATA
+                            06000100 30390000  // NFTA_DATA_VALUE
+                1c000180  // NFTA_LIST_ELEM
+                    0a000100 72656469 72000000  // NFTA_EXPR_NAME \"redir\"
+                    0c000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_REDIR_REG_PROTO_MIN 1
+        ");
+        assert_eq!(nft_rule(&rule, &rule.dport.ranges()[0]).0, expected);
+    }
+
+    #[test]
+    fn redirect_for_every_port() {
+        let rule = rule(json!({ "dport": "1:65535", "to_port": 12345, "action": "REDIRECT" }));
+
+        // tcp redirect to :12345, no port match
+        let expected = hex("
+            0d000100 70726f78 73776170 00000000  // NFTA_RULE_TABLE \"proxswap\"
+            0b000200 6f757470 75740000  // NFTA_RULE_CHAIN \"output\"
+            9c000480  // NFTA_RULE_EXPRESSIONS
+                24000180  // NFTA_LIST_ELEM
+                    09000100 6d657461 00000000  // NFTA_EXPR_NAME \"meta\"
+                    14000280  // NFTA_EXPR_DATA
+                        08000200 00000010  // NFTA_META_KEY L4PROTO 16
+                        08000100 00000001  // NFTA_META_DREG 1
+                2c000180  // NFTA_LIST_ELEM
+                    08000100 636d7000  // NFTA_EXPR_NAME \"cmp\"
+                    20000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_CMP_SREG 1
+                        08000200 00000000  // NFTA_CMP_OP EQ 0
+                        0c000380  // NFTA_CMP_DATA
+                            05000100 06000000  // NFTA_DATA_VALUE
+                2c000180  // NFTA_LIST_ELEM
+                    0e000100 696d6d65 64696174 65000000  // NFTA_EXPR_NAME \"immediate\"
+                    18000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_IMMEDIATE_DREG 1
+                        0c000280  // NFTA_IMMEDIATE_DATA
+                            06000100 30390000  // NFTA_DATA_VALUE
+                1c000180  // NFTA_LIST_ELEM
+                    0a000100 72656469 72000000  // NFTA_EXPR_NAME \"redir\"
+                    0c000280  // NFTA_EXPR_DATA
+                        08000100 00000001  // NFTA_REDIR_REG_PROTO_MIN 1
+        ");
+        assert_eq!(nft_rule(&rule, &rule.dport.ranges()[0]).0, expected);
+    }
+
+    #[test]
+    fn replace_batch_without_rules() {
+        // nlmsghdr (length, type, flags, seq, port id) and nfgenmsg, then the attributes
+        let expected = hex("
+            14000000 10000100 01000000 00000000 0000000a  // NFNL_MSG_BATCH_BEGIN
+            24000000 000a0504 02000000 00000000 02000000  // NFT_MSG_NEWTABLE, REQUEST|ACK|CREATE
+                0d000100 70726f78 73776170 00000000
+            24000000 020a0500 03000000 00000000 02000000  // NFT_MSG_DELTABLE, REQUEST|ACK
+                0d000100 70726f78 73776170 00000000
+            24000000 000a0504 04000000 00000000 02000000  // NFT_MSG_NEWTABLE, REQUEST|ACK|CREATE
+                0d000100 70726f78 73776170 00000000
+            54000000 030a0504 05000000 00000000 02000000  // NFT_MSG_NEWCHAIN, REQUEST|ACK|CREATE
+                0d000100 70726f78 73776170 00000000  // NFTA_CHAIN_TABLE \"proxswap\"
+                0b000300 6f757470 75740000  // NFTA_CHAIN_NAME \"output\"
+                14000480  // NFTA_CHAIN_HOOK
+                    08000100 00000003  // NFTA_HOOK_HOOKNUM LOCAL_OUT 3
+                    08000200 ffffff9c  // NFTA_HOOK_PRIORITY NAT_DST -100
+                08000500 00000001  // NFTA_CHAIN_POLICY ACCEPT 1
+                08000700 6e617400  // NFTA_CHAIN_TYPE \"nat\"
+            14000000 11000100 06000000 00000000 0000000a  // NFNL_MSG_BATCH_END
+        ");
+        assert_eq!(finished(replace_batch(&[])), expected);
+    }
+
+    #[test]
+    fn replace_batch_has_a_rule_per_range() {
+        let rules = [rule(json!({ "dport": "22,8000:8080", "to_port": 12345, "action": "REDIRECT" }))];
+        let mut batch = replace_batch(&rules);
+        assert_eq!(batch.finish(), 7);
+
+        let without_rules = finished(replace_batch(&[]));
+        let begin_and_setup = without_rules.len() - 20;
+        assert_eq!(batch.buffer[..begin_and_setup], without_rules[..begin_and_setup]);
+
+        let mut messages = &batch.buffer[begin_and_setup..];
+        for (seq, range) in (6..).zip(rules[0].dport.ranges()) {
+            let attributes = nft_rule(&rules[0], range).0;
+            let len = 20 + attributes.len();
+            assert_eq!(messages[..4], (len as u32).to_ne_bytes());
+            // NFT_MSG_NEWRULE, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_APPEND
+            assert_eq!(messages[4..8], hex("060a 050c"));
+            assert_eq!(messages[8..12], (seq as u32).to_ne_bytes());
+            assert_eq!(messages[16..20], hex("02000000"));
+            assert_eq!(messages[20..len], attributes);
+            messages = &messages[len..];
+        }
+        // the batch end follows
+        assert_eq!(messages[4..6], hex("1100"));
+    }
+
+    #[test]
+    fn delete_batch_drops_the_table() {
+        let expected = hex("
+            14000000 10000100 01000000 00000000 0000000a  // NFNL_MSG_BATCH_BEGIN
+            24000000 000a0504 02000000 00000000 02000000  // NFT_MSG_NEWTABLE, REQUEST|ACK|CREATE
+                0d000100 70726f78 73776170 00000000
+            24000000 020a0500 03000000 00000000 02000000  // NFT_MSG_DELTABLE, REQUEST|ACK
+                0d000100 70726f78 73776170 00000000
+            14000000 11000100 04000000 00000000 0000000a  // NFNL_MSG_BATCH_END
+        ");
+        assert_eq!(finished(delete_batch()), expected);
+    }
+}
@@ -8,3 +8,40 @@ pub static CONFIG_DIR: Lazy<String> = Lazy::new(|| {
 pub static REDSOCKS_DIR: Lazy<String> = Lazy::new(|| {
     format!("{}/redsocks", *CONFIG_DIR)
 });
+
+pub static EXPORT_DIR: Lazy<String> = Lazy::new(|| {
+    format!("{}/exports", *CONFIG_DIR)
+});
+
+// configurations with ${VARIABLES}, see template.rs
+pub static TEMPLATE_DIR: Lazy<String> = Lazy::new(|| {
+    format!("{}/templates", *CONFIG_DIR)
+});
+
+// deleted configurations, see trash.rs
+pub static TRASH_DIR: Lazy<String> = Lazy::new(|| {
+    format!("{}/trash", *CONFIG_DIR)
+});
+
+// JSON lines, one per activation, deactivation, creation and deletion
+pub static AUDIT_LOG_PATH: Lazy<String> = Lazy::new(|| {
+    format!("{}/history.jsonl", *CONFIG_DIR)
+});
+
+pub static SOCKET_PATH: Lazy<String> = Lazy::new(|| {
+    format!("{}/proxswapd.sock", *CONFIG_DIR)
+});
+
+// owned by root, the privileged helper keeps its socket and the redsocks config it generated here
+pub static HELPER_DIR: Lazy<String> = Lazy::new(|| {
+    "/run/proxswap".to_string()
+});
+
+pub static HELPER_SOCKET_PATH: Lazy<String> = Lazy::new(|| {
+    format!("{}/helper.sock", *HELPER_DIR)
+});
+
+// next to CONFIG_DIR rather than in it, where every *.json file is a configuration
+pub static SETTINGS_PATH: Lazy<String> = Lazy::new(|| {
+    format!("{}.json", *CONFIG_DIR)
+});
@@ -0,0 +1,88 @@
+use crate::helper;
+use std::fmt;
+
+// linux/capability.h
+pub const CAP_NET_BIND_SERVICE: u32 = 10;
+pub const CAP_NET_ADMIN: u32 = 12;
+
+/// How netfilter rules and redsocks get set up, picked from the privileges the process has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// CAP_NET_ADMIN (or root): nftables through netlink, in a table of its own
+    Nftables,
+    /// The privileged helper does it
+    Helper,
+    /// `sudo iptables`
+    Sudo,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Nftables => write!(f, "nftables (netlink, table ip proxswap)"),
+            Backend::Helper => write!(f, "privileged helper"),
+            Backend::Sudo => write!(f, "sudo iptables"),
+        }
+    }
+}
+
+pub async fn backend() -> Backend {
+    if has_capability(CAP_NET_ADMIN) {
+        Backend::Nftables
+    } else if helper::is_running().await {
+        Backend::Helper
+    } else {
+        Backend::Sudo
+    }
+}
+
+/// What to do about missing privileges, shown when sudo isn't an option either.
+pub const MISSING_PRIVILEGES: &str = "proxswap needs CAP_NET_ADMIN to manage netfilter rules. \
+Grant it with `sudo setcap cap_net_admin+ep $(command -v proxswap)`, \
+start the helper with `sudo proxswap helper`, or run proxswap through sudo";
+
+fn status_field(name: &str) -> Option<String> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name))
+        .map(|value| value.trim().to_string())
+}
+
+/// Whether the process runs with root's effective uid.
+pub fn is_root() -> bool {
+    status_field("Uid:")
+        .and_then(|uids| uids.split_whitespace().nth(1).map(|effective| effective == "0"))
+        .unwrap_or(false)
+}
+
+pub fn has_capability(capability: u32) -> bool {
+    status_field("CapEff:")
+        .and_then(|caps| u64::from_str_radix(&caps, 16).ok())
+        .is_some_and(|caps| caps & (1 << capability) != 0)
+}
+
+/// Human readable summary for `proxswap privileges`.
+pub async fn report() -> Vec<String> {
+    let yes_no = |present: bool| if present { "yes" } else { "no" };
+    let backend = backend().await;
+
+    let mut lines = vec![
+        format!("root: {}", yes_no(is_root())),
+        format!("CAP_NET_ADMIN: {}", yes_no(has_capability(CAP_NET_ADMIN))),
+        // redsocks listens on 14888 and up
+        format!(
+            "CAP_NET_BIND_SERVICE: {} (not needed, redsocks only listens on unprivileged ports)",
+            yes_no(has_capability(CAP_NET_BIND_SERVICE))
+        ),
+        format!("helper: {}", if helper::is_running().await { "running" } else { "not running" }),
+        format!("rules are managed with: {}", backend),
+    ];
+
+    if backend == Backend::Sudo {
+        lines.push(String::new());
+        lines.push(format!("{}.", MISSING_PRIVILEGES));
+    }
+
+    lines
+}
@@ -0,0 +1,236 @@
+use crate::configuration::{Proxy, ProxyKind};
+use crate::uri;
+use crate::validation;
+use clap::ValueEnum;
+use serde_json::Map;
+use std::collections::HashSet;
+
+/// Line formats understood by `parse_proxy_list`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Detect the format of every line on its own
+    Auto,
+    /// host:port
+    HostPort,
+    /// host:port:user:pass
+    HostPortUserPass,
+    /// user:pass@host:port
+    UserPassAtHostPort,
+    /// host,port[,user,pass], an optional header line is skipped
+    Csv,
+}
+
+pub struct ProxyList {
+    pub proxies: Vec<Proxy>,
+    /// Line number and reason of every line that couldn't be parsed.
+    pub skipped: Vec<(usize, String)>,
+    pub duplicates: usize,
+}
+
+/// Parses a proxy list, one proxy per line. Empty lines and lines starting with `#` are ignored.
+///
+/// `proxy_type` is used for lines that don't carry a scheme; full proxy URIs are accepted in
+/// `auto` mode and keep their own type.
+pub fn parse_proxy_list(contents: &str, format: ListFormat, proxy_type: ProxyKind) -> ProxyList {
+    let mut list = ProxyList {
+        proxies: Vec::new(),
+        skipped: Vec::new(),
+        duplicates: 0,
+    };
+    let mut seen = HashSet::new();
+    let mut first = true;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let is_first = std::mem::replace(&mut first, false);
+
+        let parsed = match format {
+            ListFormat::Auto => parse_auto(line, proxy_type),
+            ListFormat::HostPort => parse_colon_separated(line, proxy_type, false),
+            ListFormat::HostPortUserPass => parse_colon_separated(line, proxy_type, true),
+            ListFormat::UserPassAtHostPort => parse_user_pass_at(line, proxy_type),
+            ListFormat::Csv => parse_csv(line, proxy_type),
+        };
+
+        let proxy = match parsed.and_then(|proxy| proxy.validate().map(|_| proxy).map_err(|e| e.to_string())) {
+            Ok(proxy) => proxy,
+            // a CSV header is the first line that isn't a comment and whose port column isn't a number
+            Err(_) if is_first && is_csv_header(line, format) => continue,
+            Err(e) => {
+                list.skipped.push((index + 1, e));
+                continue;
+            }
+        };
+
+        let key = (
+            proxy.proxy_type,
+            proxy.url.to_lowercase(),
+            proxy.port,
+            proxy.login.clone(),
+            proxy.password.clone(),
+        );
+        if seen.insert(key) {
+            list.proxies.push(proxy);
+        } else {
+            list.duplicates += 1;
+        }
+    }
+
+    list
+}
+
+fn parse_auto(line: &str, proxy_type: ProxyKind) -> Result<Proxy, String> {
+    if line.contains("://") {
+        uri::parse_proxy_uri(line).map_err(|e| e.to_string())
+    } else if line.contains(',') {
+        parse_csv(line, proxy_type)
+    } else if line.contains('@') {
+        parse_user_pass_at(line, proxy_type)
+    } else {
+        let with_credentials = line.matches(':').count() == 3;
+        parse_colon_separated(line, proxy_type, with_credentials)
+    }
+}
+
+fn parse_colon_separated(line: &str, proxy_type: ProxyKind, with_credentials: bool) -> Result<Proxy, String> {
+    let fields: Vec<&str> = line.split(':').collect();
+
+    match (fields.as_slice(), with_credentials) {
+        ([host, port], false) => new_proxy(proxy_type, host, port, "", ""),
+        ([host, port, login, password], true) => new_proxy(proxy_type, host, port, login, password),
+        (_, false) => Err(format!("expected host:port, found \"{}\"", line)),
+        (_, true) => Err(format!("expected host:port:user:pass, found \"{}\"", line)),
+    }
+}
+
+fn parse_user_pass_at(line: &str, proxy_type: ProxyKind) -> Result<Proxy, String> {
+    let invalid = || format!("expected user:pass@host:port, found \"{}\"", line);
+    let (credentials, host_port) = line.rsplit_once('@').ok_or_else(invalid)?;
+    let (login, password) = credentials.split_once(':').ok_or_else(invalid)?;
+    let (host, port) = host_port.rsplit_once(':').ok_or_else(invalid)?;
+
+    new_proxy(proxy_type, host, port, login, password)
+}
+
+fn parse_csv(line: &str, proxy_type: ProxyKind) -> Result<Proxy, String> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim().trim_matches('"')).collect();
+
+    match fields.as_slice() {
+        [host, port] => new_proxy(proxy_type, host, port, "", ""),
+        [host, port, login, password] => new_proxy(proxy_type, host, port, login, password),
+        _ => Err(format!("expected host,port[,user,pass], found \"{}\"", line)),
+    }
+}
+
+fn is_csv_header(line: &str, format: ListFormat) -> bool {
+    matches!(format, ListFormat::Auto | ListFormat::Csv)
+        && line
+            .split(',')
+            .nth(1)
+            .is_some_and(|port| port.trim().trim_matches('"').parse::<u16>().is_err())
+}
+
+fn new_proxy(proxy_type: ProxyKind, host: &str, port: &str, login: &str, password: &str) -> Result<Proxy, String> {
+    Ok(Proxy {
+        proxy_type,
+        url: host.to_string(),
+        port: validation::parse_port(port).map_err(|e| e.to_string())?,
+        login: login.to_string(),
+        password: password.to_string(),
+        extra: Map::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(list: &ProxyList) -> Vec<(ProxyKind, &str, u16, &str, &str)> {
+        list.proxies
+            .iter()
+            .map(|proxy| (proxy.proxy_type, proxy.url.as_str(), proxy.port, proxy.login.as_str(), proxy.password.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn explicit_formats() {
+        let list = parse_proxy_list("10.0.0.1:1080\n", ListFormat::HostPort, ProxyKind::Socks5);
+        assert_eq!(summary(&list), [(ProxyKind::Socks5, "10.0.0.1", 1080, "", "")]);
+
+        let list = parse_proxy_list("host:3128:user:pass\n", ListFormat::HostPortUserPass, ProxyKind::HttpConnect);
+        assert_eq!(summary(&list), [(ProxyKind::HttpConnect, "host", 3128, "user", "pass")]);
+
+        let list = parse_proxy_list("user:p@ss@host:1080\n", ListFormat::UserPassAtHostPort, ProxyKind::Socks4);
+        assert_eq!(summary(&list), [(ProxyKind::Socks4, "host", 1080, "user", "p@ss")]);
+
+        let list = parse_proxy_list("host, 1080 ,\"user\",\"pass\"\n", ListFormat::Csv, ProxyKind::Socks5);
+        assert_eq!(summary(&list), [(ProxyKind::Socks5, "host", 1080, "user", "pass")]);
+    }
+
+    #[test]
+    fn auto_detects_every_line() {
+        let contents = "\
+# a comment
+
+a:1080
+b:1081:user:pass
+user:pass@c:1082
+d,1083
+http://e:8080
+";
+        let list = parse_proxy_list(contents, ListFormat::Auto, ProxyKind::Socks5);
+        assert_eq!(
+            summary(&list),
+            [
+                (ProxyKind::Socks5, "a", 1080, "", ""),
+                (ProxyKind::Socks5, "b", 1081, "user", "pass"),
+                (ProxyKind::Socks5, "c", 1082, "user", "pass"),
+                (ProxyKind::Socks5, "d", 1083, "", ""),
+                (ProxyKind::HttpConnect, "e", 8080, "", ""),
+            ]
+        );
+        assert!(list.skipped.is_empty());
+    }
+
+    #[test]
+    fn csv_header_is_skipped_only_on_the_first_line() {
+        let list = parse_proxy_list("host,port,user,pass\na,1080,u,p\nhost,port\n", ListFormat::Csv, ProxyKind::Socks5);
+        assert_eq!(summary(&list), [(ProxyKind::Socks5, "a", 1080, "u", "p")]);
+        assert_eq!(list.skipped.len(), 1);
+        assert_eq!(list.skipped[0].0, 3);
+
+        // after comments and empty lines
+        let list = parse_proxy_list("# exported proxies\n\nhost,port\na,1080\n", ListFormat::Auto, ProxyKind::Socks5);
+        assert_eq!(summary(&list), [(ProxyKind::Socks5, "a", 1080, "", "")]);
+        assert!(list.skipped.is_empty());
+
+        // a header isn't a format of its own for host:port lists
+        let list = parse_proxy_list("host:port\n", ListFormat::HostPort, ProxyKind::Socks5);
+        assert_eq!(list.skipped.len(), 1);
+    }
+
+    #[test]
+    fn skipped_lines_keep_their_line_number() {
+        let contents = "a:1080\n\n# comment\nb:99999\nc\na:1080:x\nftp://d:21\n";
+        let list = parse_proxy_list(contents, ListFormat::HostPort, ProxyKind::Socks5);
+        assert_eq!(list.proxies.len(), 1);
+        let lines: Vec<usize> = list.skipped.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [4, 5, 6, 7]);
+
+        let list = parse_proxy_list("a:1080\nb:1081:user\n", ListFormat::HostPortUserPass, ProxyKind::Socks5);
+        assert_eq!(list.proxies.len(), 0);
+        assert_eq!(list.skipped.len(), 2);
+    }
+
+    #[test]
+    fn duplicates_are_counted_once() {
+        let contents = "host:1080\nHOST:1080\nhost:1080:user:pass\nhost:1081\nsocks4://host:1080\n";
+        let list = parse_proxy_list(contents, ListFormat::Auto, ProxyKind::Socks5);
+        // the same host in another case is the same proxy, other credentials, ports or types aren't
+        assert_eq!(list.proxies.len(), 4);
+        assert_eq!(list.duplicates, 1);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::audit::{self, Action, Record};
+use crate::client::Client;
+use crate::health;
+use crate::loader;
+use crate::network;
+use crate::paths::*;
+use crate::state;
+use std::time::{Duration, Instant};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Re-applies the configuration that was active before the reboot.
+///
+/// Waits up to `timeout` for a default route and for the proxies to accept connections. The
+/// configuration is applied either way, so the connections its rules redirect fail instead of
+/// going around an unreachable proxy. Nothing else is blocked.
+pub async fn restore(timeout: Duration) -> anyhow::Result<()> {
+    let (mut configurations, _) = loader::load_configurations_dir(&CONFIG_DIR).await;
+    let Some(index) = state::find_active_config(&configurations) else {
+        println!("No configuration was active");
+        return Ok(());
+    };
+    let config = configurations.swap_remove(index);
+    let deadline = Instant::now() + timeout;
+
+    while network::default_route().is_none() {
+        if Instant::now() >= deadline {
+            eprintln!("The network isn't online after {}s, applying {} anyway", timeout.as_secs(), config.name);
+            break;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+
+    let unreachable = loop {
+        let unreachable: Vec<String> = config
+            .proxies
+            .iter()
+            .zip(health::check_proxies(&config.proxies, CHECK_TIMEOUT).await)
+            .filter_map(|(proxy, result)| result.err().map(|e| format!("{}:{} ({})", proxy.url, proxy.port, e)))
+            .collect();
+
+        if unreachable.is_empty() || Instant::now() >= deadline {
+            break unreachable;
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    };
+
+    // proxswapd owns activation while it runs
+    match Client::connect().await {
+        Ok(mut daemon) => daemon.activate(&config.name).await?,
+        Err(_) => {
+            let result = config.run().await;
+            let _ = Record::new(&audit::current_user(), Action::Activate).configuration(&config).outcome(&result).append();
+            result?;
+            state::write_active_config(Some(&config.name))?;
+        }
+    }
+
+    if !unreachable.is_empty() {
+        anyhow::bail!(
+            "Applied {} but its proxies are unreachable: {}. Connections its rules redirect fail until they come back",
+            config.name,
+            unreachable.join(", ")
+        );
+    }
+
+    println!("Restored {}", config.name);
+    Ok(())
+}
+
+/// A systemd unit that runs `proxswap restore` for the current user once the network is online.
+pub fn systemd_unit(timeout: Duration) -> anyhow::Result<String> {
+    let exe = std::env::current_exe()?;
+    let home = std::env::var("HOME")?;
+    let uid = unsafe { libc::getuid() };
+
+    let mut service = vec![
+        "Type=oneshot".to_string(),
+        "RemainAfterExit=yes".to_string(),
+        format!("Environment=HOME={}", home),
+        format!("ExecStart={} restore --timeout {}", exe.display(), timeout.as_secs()),
+        format!("TimeoutStartSec={}", timeout.as_secs() + 30),
+    ];
+    // ordinary users get CAP_NET_ADMIN instead of root, so nftables is used and sudo never asked
+    if uid != 0 {
+        service.push(format!("User={}", uid));
+        service.push("AmbientCapabilities=CAP_NET_ADMIN".to_string());
+        service.push("CapabilityBoundingSet=CAP_NET_ADMIN".to_string());
+    }
+
+    Ok(format!(
+        "[Unit]\n\
+         Description=Restore the active proxswap configuration\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         {}\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        service.join("\n")
+    ))
+}
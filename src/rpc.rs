@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// JSON-RPC 2.0 error codes, the application ones are from the implementation-defined range
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const NOT_FOUND: i64 = -32001;
+pub const ACTIVATION_FAILED: i64 = -32002;
+pub const NOT_ALLOWED: i64 = -32003;
+// the helper's rules failed after redsocks started, so the activation's redsocks is running
+pub const RULES_FAILED: i64 = -32004;
+
+/// A request from a client, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// None for a notification, which gets no response. `"id": null` is a request.
+    #[serde(default, deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+// Some even for null, so that only a missing id makes a notification
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// Either a response to a request (with `id`) or an event notification (with `method`).
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Message {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Reads a request line. For an invalid one, returns the error to respond with and the request's
+/// id, null if it couldn't be read.
+pub fn parse_request(line: &str) -> Result<Request, (Value, RpcError)> {
+    let value: Value =
+        serde_json::from_str(line).map_err(|e| (Value::Null, RpcError::new(PARSE_ERROR, e.to_string())))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let invalid = |message: String| (id.clone(), RpcError::new(INVALID_REQUEST, message));
+
+    let request: Request = serde_json::from_value(value).map_err(|e| invalid(e.to_string()))?;
+    if request.jsonrpc != "2.0" {
+        return Err(invalid(format!("unsupported JSON-RPC version \"{}\", expected \"2.0\"", request.jsonrpc)));
+    }
+
+    Ok(request)
+}
+
+impl Message {
+    pub fn response(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        Message {
+            jsonrpc: "2.0".to_string(),
+            id: Some(id),
+            result,
+            error,
+            ..Default::default()
+        }
+    }
+
+    pub fn event(event: &Event) -> Self {
+        Message {
+            jsonrpc: "2.0".to_string(),
+            method: Some("event".to_string()),
+            params: serde_json::to_value(event).ok(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Sent to subscribed clients whenever the daemon's state changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Activated { name: String },
+    Deactivated,
+    Health { name: String, proxies: Vec<ProxyStatus> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigurationSummary {
+    pub name: String,
+    pub proxies: usize,
+    pub rules: usize,
+    pub active: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Status {
+    pub active: Option<String>,
+    /// Health of the active configuration's proxies, empty until the first check finished.
+    pub proxies: Vec<ProxyStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyStatus {
+    pub url: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ProxyStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Traffic {
+    pub connections: u64,
+    /// Connections that ended before the proxy side sent anything
+    pub errors: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConfigurationTraffic {
+    pub name: String,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+    /// Keyed by proxy host:port
+    pub proxies: BTreeMap<String, Traffic>,
+    /// Keyed by original destination ip:port, past the first 1024 destinations under "other"
+    pub destinations: BTreeMap<String, Traffic>,
+}
+
+/// A connection going through the active configuration right now.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveConnection {
+    pub destination: String,
+    pub proxy: String,
+    pub age_secs: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Statistics {
+    /// Whether the kernel counts bytes, without it only connections are counted
+    pub accounting: bool,
+    /// Since when proxswapd has been counting, in seconds since the epoch
+    pub since: u64,
+    pub configurations: Vec<ConfigurationTraffic>,
+    pub connections: Vec<LiveConnection>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn error(line: &str) -> (Value, i64) {
+        let (id, error) = parse_request(line).unwrap_err();
+        (id, error.code)
+    }
+
+    #[test]
+    fn requests_and_notifications() {
+        let request = parse_request(r#"{"jsonrpc": "2.0", "id": 1, "method": "activate", "params": {"name": "office"}}"#).unwrap();
+        assert_eq!(request.id, Some(json!(1)));
+        assert_eq!(request.method, "activate");
+        assert_eq!(request.params, json!({ "name": "office" }));
+
+        // only a missing id makes a notification
+        let notification = parse_request(r#"{"jsonrpc": "2.0", "method": "deactivate"}"#).unwrap();
+        assert_eq!(notification.id, None);
+        assert_eq!(notification.params, Value::Null);
+        let null_id = parse_request(r#"{"jsonrpc": "2.0", "id": null, "method": "status"}"#).unwrap();
+        assert_eq!(null_id.id, Some(Value::Null));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert_eq!(error("{\"jsonrpc\": \"2.0\", "), (Value::Null, PARSE_ERROR));
+        assert_eq!(error(r#"{"jsonrpc": "2.0", "id": 3}"#), (json!(3), INVALID_REQUEST));
+        assert_eq!(error(r#"{"id": "a", "method": "status"}"#), (json!("a"), INVALID_REQUEST));
+        assert_eq!(error(r#"{"jsonrpc": "1.0", "id": 4, "method": "status"}"#), (json!(4), INVALID_REQUEST));
+        assert_eq!(error(r#"{"jsonrpc": 2, "method": "status"}"#), (Value::Null, INVALID_REQUEST));
+        assert_eq!(error("[1, 2]"), (Value::Null, INVALID_REQUEST));
+    }
+
+    #[test]
+    fn responses_carry_either_a_result_or_an_error() {
+        assert_eq!(
+            serde_json::to_value(Message::response(json!(1), Ok(json!(true)))).unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": true })
+        );
+        assert_eq!(
+            serde_json::to_value(Message::response(json!(2), Err(RpcError::new(NOT_FOUND, "missing")))).unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 2, "error": { "code": NOT_FOUND, "message": "missing" } })
+        );
+        // a null result is still a result
+        assert_eq!(
+            serde_json::to_value(Message::response(json!(3), Ok(Value::Null))).unwrap(),
+            json!({ "jsonrpc": "2.0", "id": 3, "result": null })
+        );
+    }
+
+    #[test]
+    fn events_are_notifications() {
+        assert_eq!(
+            serde_json::to_value(Message::event(&Event::Activated { name: "office".to_string() })).unwrap(),
+            json!({ "jsonrpc": "2.0", "method": "event", "params": { "type": "activated", "name": "office" } })
+        );
+        let event: Event = serde_json::from_value(json!({ "type": "deactivated" })).unwrap();
+        assert!(matches!(event, Event::Deactivated));
+    }
+}
@@ -0,0 +1,295 @@
+use crate::validation::ValidationError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+pub const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+/// A weekly time window in local time, e.g. `mon-fri 09:00-18:00`. Without days it applies to
+/// every day, and a window ending at or before its start runs past midnight.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    /// bit n is set for the n-th day of `DAYS`
+    pub days: u8,
+    /// minutes since midnight
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Window {
+    const EVERY_DAY: u8 = 0b111_1111;
+
+    fn len(&self) -> u32 {
+        if self.end > self.start {
+            self.end - self.start
+        } else {
+            self.end + MINUTES_PER_DAY - self.start
+        }
+    }
+
+    /// Start of every occurrence in minutes since Monday 00:00, with the window's length.
+    fn occurrences(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..7u32)
+            .filter(|day| self.days & (1 << day) != 0)
+            .map(|day| (day * MINUTES_PER_DAY + self.start, self.len()))
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.days != Window::EVERY_DAY {
+            // consecutive days are written as ranges: mon-wed,fri
+            let mut ranges = Vec::new();
+            let mut day = 0;
+            while day < 7 {
+                if self.days & (1 << day) == 0 {
+                    day += 1;
+                    continue;
+                }
+                let first = day;
+                while day + 1 < 7 && self.days & (1 << (day + 1)) != 0 {
+                    day += 1;
+                }
+                ranges.push(if first == day {
+                    DAYS[first].to_string()
+                } else {
+                    format!("{}-{}", DAYS[first], DAYS[day])
+                });
+                day += 1;
+            }
+            write!(f, "{} ", ranges.join(","))?;
+        }
+
+        write!(f, "{}-{}", format_time(self.start), format_time(self.end))
+    }
+}
+
+impl FromStr for Window {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValidationError::InvalidSchedule(s.to_string());
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim()).ok_or_else(invalid)?, times),
+            None => (Window::EVERY_DAY, s.trim()),
+        };
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start).filter(|&start| start < MINUTES_PER_DAY).ok_or_else(invalid)?;
+        let end = parse_time(end).ok_or_else(invalid)?;
+
+        Ok(Window { days, start, end })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = ValidationError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> Self {
+        window.to_string()
+    }
+}
+
+fn parse_day(day: &str) -> Option<u32> {
+    DAYS.iter().position(|name| name.eq_ignore_ascii_case(day)).map(|index| index as u32)
+}
+
+// mon, mon-fri, sat,sun or a comma separated mix
+fn parse_days(days: &str) -> Option<u8> {
+    let mut mask = 0u8;
+
+    for part in days.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last) = (parse_day(first.trim())?, parse_day(last.trim())?);
+        if first > last {
+            return None;
+        }
+        for day in first..=last {
+            mask |= 1 << day;
+        }
+    }
+
+    Some(mask)
+}
+
+// HH:MM, 24:00 is accepted as the end of the day
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+
+    match (hours, minutes) {
+        (24, 0) => Some(MINUTES_PER_DAY),
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+fn format_time(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// The windows a configuration is active in, empty for configurations without a schedule.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule(Vec<Window>);
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.0
+    }
+
+    /// Whether `minute` (minutes since Monday 00:00) is inside one of the windows.
+    pub fn contains(&self, minute: u32) -> bool {
+        self.0
+            .iter()
+            .flat_map(Window::occurrences)
+            .any(|(start, len)| (minute + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK < len)
+    }
+
+    /// Minutes from `minute` until the next window starts.
+    pub fn next_start(&self, minute: u32) -> Option<u32> {
+        self.0
+            .iter()
+            .flat_map(Window::occurrences)
+            .map(|(start, _)| (start + MINUTES_PER_WEEK - minute) % MINUTES_PER_WEEK)
+            .min()
+    }
+}
+
+/// Minutes since Monday 00:00 in local time.
+pub fn minute_of_week() -> u32 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut local: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut local);
+
+        // tm_wday counts from Sunday
+        let day = (local.tm_wday as u32 + 6) % 7;
+        day * MINUTES_PER_DAY + local.tm_hour as u32 * 60 + local.tm_min as u32
+    }
+}
+
+/// `minute` (minutes since Monday 00:00) as e.g. "tue 09:00".
+pub fn format_minute_of_week(minute: u32) -> String {
+    let minute = minute % MINUTES_PER_WEEK;
+    format!("{} {}", DAYS[(minute / MINUTES_PER_DAY) as usize], format_time(minute % MINUTES_PER_DAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(windows: &[&str]) -> Schedule {
+        Schedule(windows.iter().map(|window| window.parse().unwrap()).collect())
+    }
+
+    // minutes since Monday 00:00
+    fn at(day: &str, time: &str) -> u32 {
+        parse_day(day).unwrap() * MINUTES_PER_DAY + parse_time(time).unwrap()
+    }
+
+    #[test]
+    fn parses_days_and_times() {
+        let window: Window = "mon-wed,fri 09:00-18:30".parse().unwrap();
+        assert_eq!(window, Window { days: 0b001_0111, start: 540, end: 1110 });
+        assert_eq!(window.to_string(), "mon-wed,fri 09:00-18:30");
+
+        let window: Window = "22:00-24:00".parse().unwrap();
+        assert_eq!(window, Window { days: Window::EVERY_DAY, start: 1320, end: MINUTES_PER_DAY });
+        assert_eq!(window.to_string(), "22:00-24:00");
+
+        let window: Window = " SAT,Sun 00:00-06:00 ".parse().unwrap();
+        assert_eq!(window.days, 0b110_0000);
+        assert_eq!(window.to_string(), "sat-sun 00:00-06:00");
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for window in [
+            "",
+            "09:00",
+            "9:00-18:00",
+            "09:00-18:0",
+            "24:00-06:00",
+            "09:00-24:01",
+            "09:60-10:00",
+            "fri-mon 09:00-18:00",
+            "mon,,tue 09:00-18:00",
+            "monday 09:00-18:00",
+            "mon 09:00 18:00",
+        ] {
+            assert!(window.parse::<Window>().is_err(), "{:?} should be rejected", window);
+        }
+    }
+
+    #[test]
+    fn window_inside_a_day() {
+        let schedule = schedule(&["mon-fri 09:00-18:00"]);
+        assert!(!schedule.contains(at("mon", "08:59")));
+        assert!(schedule.contains(at("mon", "09:00")));
+        assert!(schedule.contains(at("fri", "17:59")));
+        assert!(!schedule.contains(at("fri", "18:00")));
+        assert!(!schedule.contains(at("sat", "12:00")));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let schedule = schedule(&["fri 22:00-02:00"]);
+        assert!(!schedule.contains(at("fri", "21:59")));
+        assert!(schedule.contains(at("fri", "23:59")));
+        // the rest of the window is on the next day, which isn't listed
+        assert!(schedule.contains(at("sat", "00:00")));
+        assert!(schedule.contains(at("sat", "01:59")));
+        assert!(!schedule.contains(at("sat", "02:00")));
+        assert!(!schedule.contains(at("thu", "23:00")));
+    }
+
+    #[test]
+    fn window_wraps_from_sunday_into_monday() {
+        let schedule = schedule(&["sun 23:00-01:00"]);
+        assert!(schedule.contains(at("sun", "23:30")));
+        assert!(schedule.contains(at("mon", "00:00")));
+        assert!(schedule.contains(at("mon", "00:59")));
+        assert!(!schedule.contains(at("mon", "01:00")));
+        assert!(!schedule.contains(at("sat", "23:30")));
+
+        assert_eq!(schedule.next_start(at("mon", "01:00")), Some(at("sun", "23:00") - at("mon", "01:00")));
+        assert_eq!(schedule.next_start(at("sun", "23:00")), Some(0));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_a_whole_day() {
+        let schedule = schedule(&["wed 12:00-12:00"]);
+        assert!(schedule.contains(at("wed", "12:00")));
+        assert!(schedule.contains(at("thu", "11:59")));
+        assert!(!schedule.contains(at("thu", "12:00")));
+        assert!(!schedule.contains(at("wed", "11:59")));
+    }
+
+    #[test]
+    fn empty_schedule() {
+        let schedule = Schedule::default();
+        assert!(!schedule.contains(at("mon", "00:00")));
+        assert_eq!(schedule.next_start(0), None);
+    }
+
+    #[test]
+    fn formats_minute_of_week() {
+        assert_eq!(format_minute_of_week(at("tue", "09:05")), "tue 09:05");
+        assert_eq!(format_minute_of_week(MINUTES_PER_WEEK + 1), "mon 00:01");
+    }
+}
@@ -0,0 +1,366 @@
+use crate::configuration::{Configuration, RuleAction};
+
+// scores of the fuzzy matcher, the same idea as fzf's but simpler
+const SCORE_MATCH: i64 = 16;
+const BONUS_BOUNDARY: i64 = 8;
+const BONUS_CONSECUTIVE: i64 = 8;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP_EXTENSION: i64 = 1;
+// a match in the name ranks above the same match anywhere else
+const BONUS_NAME: i64 = 8;
+
+/// Part of a configuration a term matched, to highlight it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Name,
+    Group,
+    Tag(usize),
+    /// `url:port` of the proxy at that index
+    Proxy(usize),
+}
+
+/// How well a configuration matches a query, and which characters (by index) matched.
+#[derive(Debug, Clone, Default)]
+pub struct Match {
+    pub score: i64,
+    pub highlights: Vec<(Field, Vec<usize>)>,
+}
+
+impl Match {
+    pub fn positions(&self, field: Field) -> &[usize] {
+        self.highlights
+            .iter()
+            .find(|(highlighted, _)| *highlighted == field)
+            .map(|(_, positions)| positions.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn add(&mut self, field: Field, score: i64, positions: Vec<usize>) {
+        self.score += score;
+        match self.highlights.iter_mut().find(|(highlighted, _)| *highlighted == field) {
+            Some((_, existing)) => {
+                existing.extend(positions);
+                existing.sort_unstable();
+                existing.dedup();
+            }
+            None => self.highlights.push((field, positions)),
+        }
+    }
+}
+
+enum Term {
+    /// Free text, matched against the name, group, tags and proxies
+    Any(String),
+    Name(String),
+    Group(String),
+    Tag(String),
+    Host(String),
+    /// Prefix of the proxy type, so `type:http` finds both HTTP types
+    Type(String),
+    /// A proxy's port or a port a rule redirects
+    Port(String),
+}
+
+/// A search: whitespace separated terms that all have to match. `host:`, `type:`, `port:`,
+/// `tag:`, `group:` and `name:` limit a term to that field.
+pub struct Query(Vec<Term>);
+
+impl Query {
+    pub fn parse(query: &str) -> Query {
+        let terms = query
+            .split_whitespace()
+            .filter_map(|word| {
+                let Some((field, value)) = word.split_once(':') else {
+                    return Some(Term::Any(word.to_lowercase()));
+                };
+                let value = value.to_lowercase();
+                let term = match field.to_lowercase().as_str() {
+                    // a qualifier that is still being typed
+                    "name" | "group" | "tag" | "host" | "type" | "port" if value.is_empty() => return None,
+                    "name" => Term::Name(value),
+                    "group" => Term::Group(value),
+                    "tag" => Term::Tag(value),
+                    "host" => Term::Host(value),
+                    "type" => Term::Type(value),
+                    "port" => Term::Port(value),
+                    // e.g. "10.0.0.1:3128"
+                    _ => Term::Any(word.to_lowercase()),
+                };
+                Some(term)
+            })
+            .collect();
+
+        Query(terms)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// None unless every term matches.
+    pub fn matches(&self, config: &Configuration) -> Option<Match> {
+        let mut result = Match::default();
+
+        for term in self.0.iter() {
+            let (field, score, positions) = match term {
+                Term::Any(pattern) => fields(config)
+                    .filter_map(|(field, text)| {
+                        let (score, positions) = fuzzy_match(pattern, &text)?;
+                        let bonus = if field == Field::Name { BONUS_NAME } else { 0 };
+                        Some((field, score + bonus, positions))
+                    })
+                    .max_by_key(|(_, score, _)| *score)?,
+                Term::Name(pattern) => best(pattern, fields(config).filter(|(field, _)| *field == Field::Name))?,
+                Term::Group(pattern) => best(pattern, fields(config).filter(|(field, _)| *field == Field::Group))?,
+                Term::Tag(pattern) => best(pattern, fields(config).filter(|(field, _)| matches!(field, Field::Tag(_))))?,
+                Term::Host(pattern) => best(
+                    pattern,
+                    config.proxies.iter().enumerate().map(|(i, proxy)| (Field::Proxy(i), proxy.url.clone())),
+                )?,
+                Term::Type(prefix) => {
+                    let i = config.proxies.iter().position(|proxy| proxy.proxy_type.as_str().starts_with(prefix.as_str()))?;
+                    // nothing to highlight, the type isn't in the list
+                    (Field::Proxy(i), SCORE_MATCH, Vec::new())
+                }
+                Term::Port(port) => {
+                    let port: u16 = port.parse().ok()?;
+                    match config.proxies.iter().position(|proxy| proxy.port == port) {
+                        Some(i) => {
+                            // highlight the port after "url:"
+                            let proxy = &config.proxies[i];
+                            let start = proxy.url.chars().count() + 1;
+                            let positions = (start..start + port.to_string().len()).collect();
+                            (Field::Proxy(i), SCORE_MATCH, positions)
+                        }
+                        None if redirects(config, port) => (Field::Name, SCORE_MATCH, Vec::new()),
+                        None => return None,
+                    }
+                }
+            };
+            result.add(field, score, positions);
+        }
+
+        Some(result)
+    }
+}
+
+/// The text of the fields free text is matched against.
+fn fields(config: &Configuration) -> impl Iterator<Item = (Field, String)> + '_ {
+    std::iter::once((Field::Name, config.name.clone()))
+        .chain(config.group.iter().map(|group| (Field::Group, group.clone())))
+        .chain(config.tags.iter().enumerate().map(|(i, tag)| (Field::Tag(i), tag.clone())))
+        .chain(
+            config
+                .proxies
+                .iter()
+                .enumerate()
+                .map(|(i, proxy)| (Field::Proxy(i), format!("{}:{}", proxy.url, proxy.port))),
+        )
+}
+
+fn best(pattern: &str, candidates: impl Iterator<Item = (Field, String)>) -> Option<(Field, i64, Vec<usize>)> {
+    candidates
+        .filter_map(|(field, text)| {
+            let (score, positions) = fuzzy_match(pattern, &text)?;
+            Some((field, score, positions))
+        })
+        .max_by_key(|(_, score, _)| *score)
+}
+
+fn redirects(config: &Configuration, port: u16) -> bool {
+    config.rules.iter().any(|rule| {
+        rule.action == RuleAction::Redirect
+            && rule.dport.ranges().iter().any(|range| range.start <= port && port <= range.end)
+    })
+}
+
+/// Matches `pattern` (lower case) as a subsequence of `text`, ignoring case. Returns the score
+/// of the best alignment and the indices of the matched characters in `text`. Runs of
+/// consecutive characters and characters at the start of a word score higher, gaps cost.
+fn fuzzy_match(pattern: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    if pattern.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    if pattern.len() > text.len() {
+        return None;
+    }
+
+    let bonus: Vec<i64> = (0..text.len())
+        .map(|j| match j.checked_sub(1).map(|k| text[k]) {
+            None => BONUS_BOUNDARY,
+            Some(previous) if !previous.is_alphanumeric() => BONUS_BOUNDARY,
+            Some(previous) if previous.is_lowercase() && text[j].is_uppercase() => BONUS_BOUNDARY,
+            Some(_) => 0,
+        })
+        .collect();
+    let matches = |i: usize, j: usize| text[j].to_lowercase().eq(pattern[i].to_lowercase());
+
+    // score[i][j]: best score with pattern[..=i] matched and pattern[i] at text[j],
+    // from[i][j]: where pattern[i - 1] is then
+    let mut score = vec![vec![None; text.len()]; pattern.len()];
+    let mut from = vec![vec![0; text.len()]; pattern.len()];
+    for j in 0..text.len() {
+        if matches(0, j) {
+            score[0][j] = Some(SCORE_MATCH + bonus[j]);
+        }
+    }
+    for i in 1..pattern.len() {
+        for j in i..text.len() {
+            if !matches(i, j) {
+                continue;
+            }
+            let previous = (i - 1..j)
+                .filter_map(|k| {
+                    let gap = (j - k - 1) as i64;
+                    let transition = if gap == 0 {
+                        BONUS_CONSECUTIVE
+                    } else {
+                        -PENALTY_GAP_START - PENALTY_GAP_EXTENSION * (gap - 1)
+                    };
+                    Some((score[i - 1][k]? + transition, k))
+                })
+                .max_by_key(|&(score, k)| (score, k));
+            if let Some((previous, k)) = previous {
+                score[i][j] = Some(previous + SCORE_MATCH + bonus[j]);
+                from[i][j] = k;
+            }
+        }
+    }
+
+    let last = pattern.len() - 1;
+    let (best, mut j) = (0..text.len())
+        .filter_map(|j| Some((score[last][j]?, j)))
+        // the earliest end on ties
+        .max_by_key(|&(score, j)| (score, std::cmp::Reverse(j)))?;
+
+    let mut positions = vec![j];
+    for i in (1..pattern.len()).rev() {
+        j = from[i][j];
+        positions.push(j);
+    }
+    positions.reverse();
+
+    Some((best, positions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(name: &str) -> Configuration {
+        serde_json::from_value(json!({
+            "version": 1,
+            "name": name,
+            "proxies": [
+                { "proxy_type": "socks5", "url": "10.0.0.1", "port": 1080 },
+                { "proxy_type": "http-connect", "url": "proxy.example.com", "port": 3128 },
+            ],
+            "rules": [{ "dport": "8000:8100", "to_port": 14888, "action": "REDIRECT" }],
+            "group": "Work",
+            "tags": ["vpn", "eu-west"],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn fuzzy_match_is_a_case_insensitive_subsequence() {
+        assert_eq!(fuzzy_match("", "anything"), Some((0, Vec::new())));
+        assert_eq!(fuzzy_match("abc", "ab"), None);
+        assert_eq!(fuzzy_match("ba", "abc"), None);
+        assert_eq!(fuzzy_match("wrk", "Work").map(|(_, positions)| positions), Some(vec![0, 2, 3]));
+        assert_eq!(fuzzy_match("é", "Café").map(|(_, positions)| positions), Some(vec![3]));
+    }
+
+    #[test]
+    fn fuzzy_match_prefers_runs_and_word_starts() {
+        // a run beats the same characters spread out
+        let (run, _) = fuzzy_match("prx", "prx-proxy").unwrap();
+        let (spread, _) = fuzzy_match("prx", "proxy").unwrap();
+        assert!(run > spread);
+
+        // the start of a word beats the middle of one
+        assert_eq!(fuzzy_match("w", "eu-west").map(|(_, positions)| positions), Some(vec![3]));
+        assert_eq!(fuzzy_match("w", "newWest").map(|(_, positions)| positions), Some(vec![3]));
+        assert_eq!(fuzzy_match("hm", "home-mobile").map(|(_, positions)| positions), Some(vec![0, 5]));
+    }
+
+    #[test]
+    fn fuzzy_match_breaks_ties_by_the_earliest_end() {
+        assert_eq!(fuzzy_match("ab", "ab ab"), Some((2 * SCORE_MATCH + BONUS_BOUNDARY + BONUS_CONSECUTIVE, vec![0, 1])));
+        assert_eq!(fuzzy_match("a", "x-a-a").map(|(_, positions)| positions), Some(vec![2]));
+    }
+
+    #[test]
+    fn parse_splits_terms_and_qualifiers() {
+        assert!(Query::parse("").is_empty());
+        assert!(Query::parse("   ").is_empty());
+        // qualifiers still being typed are ignored
+        assert!(Query::parse("host: tag:").is_empty());
+
+        let query = Query::parse("NAME:Office host:Proxy 10.0.0.1:1080 x:y");
+        let terms: Vec<&str> = query
+            .0
+            .iter()
+            .map(|term| match term {
+                Term::Any(text) => text.as_str(),
+                Term::Name(text) => text.as_str(),
+                Term::Host(text) => text.as_str(),
+                _ => "?",
+            })
+            .collect();
+        assert_eq!(terms, ["office", "proxy", "10.0.0.1:1080", "x:y"]);
+        assert!(matches!(query.0[0], Term::Name(_)));
+        assert!(matches!(query.0[2], Term::Any(_)));
+        assert!(matches!(query.0[3], Term::Any(_)));
+    }
+
+    #[test]
+    fn every_term_has_to_match() {
+        let config = config("office");
+        assert!(Query::parse("off").matches(&config).is_some());
+        assert!(Query::parse("off home").matches(&config).is_none());
+        assert!(Query::parse("name:work").matches(&config).is_none());
+        assert!(Query::parse("group:work tag:vpn").matches(&config).is_some());
+    }
+
+    #[test]
+    fn qualifiers_limit_the_field() {
+        let config = config("office");
+
+        let found = Query::parse("host:example").matches(&config).unwrap();
+        assert_eq!(found.positions(Field::Proxy(1)), (6..13).collect::<Vec<_>>());
+
+        assert!(Query::parse("type:http").matches(&config).is_some());
+        assert!(Query::parse("type:socks4").matches(&config).is_none());
+
+        // the port of a proxy is highlighted after "url:"
+        let found = Query::parse("port:3128").matches(&config).unwrap();
+        assert_eq!(found.positions(Field::Proxy(1)), [18, 19, 20, 21]);
+        // a port the rules redirect matches too
+        assert!(Query::parse("port:8050").matches(&config).is_some());
+        assert!(Query::parse("port:8101").matches(&config).is_none());
+        assert!(Query::parse("port:http").matches(&config).is_none());
+
+        let found = Query::parse("tag:west").matches(&config).unwrap();
+        assert_eq!(found.positions(Field::Tag(1)), [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn name_matches_rank_first() {
+        // "work" is both the group and a configuration's name
+        let by_group = Query::parse("work").matches(&config("office")).unwrap();
+        let by_name = Query::parse("work").matches(&config("work")).unwrap();
+        assert!(by_name.score > by_group.score);
+        assert_eq!(by_name.positions(Field::Name), [0, 1, 2, 3]);
+        assert!(by_name.positions(Field::Group).is_empty());
+    }
+
+    #[test]
+    fn highlights_of_several_terms_are_merged() {
+        let found = Query::parse("of ice").matches(&config("office")).unwrap();
+        assert_eq!(found.positions(Field::Name), [0, 1, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::network::NetworkRule;
+use crate::paths::*;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+
+/// Global settings from `SETTINGS_PATH`, every key is optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub on_exit: ExitPolicy,
+    /// Rules proxswapd uses to pick a configuration for the network, the first match wins
+    pub auto_switch: Vec<NetworkRule>,
+    /// Where proxswapd serves Prometheus metrics, off when unset
+    pub metrics_address: Option<SocketAddr>,
+    /// Lets proxswapd turn on the kernel's conntrack byte counters and timestamps, host-wide
+    pub conntrack_accounting: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// What happens to the active configuration when the TUI exits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitPolicy {
+    /// Leave redsocks and the rules in place
+    #[default]
+    Keep,
+    /// Remove redsocks and the rules
+    Deactivate,
+    /// Ask before quitting
+    Ask,
+}
+
+impl Settings {
+    /// Reads the settings file, a missing file means the defaults.
+    pub fn load() -> anyhow::Result<Settings> {
+        match std::fs::read_to_string(&*SETTINGS_PATH) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Settings::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::configuration::{write_atomic, Configuration};
+use crate::paths::*;
+use std::path::Path;
+
+fn active_config_path() -> String {
+    format!("{}/active_config.txt", *CONFIG_DIR)
+}
+
+/// Name of the configuration that was activated last, `None` once the proxy is deactivated.
+pub fn read_active_config() -> Option<String> {
+    let val = std::fs::read_to_string(active_config_path()).ok()?;
+    let trimmed = val.trim();
+
+    // "None" is what older versions wrote after deactivating
+    if trimmed.is_empty() || trimmed == "None" {
+        None
+    } else {
+        Some(trimmed.to_string())
+    }
+}
+
+pub fn write_active_config(name: Option<&str>) -> anyhow::Result<()> {
+    write_atomic(Path::new(&active_config_path()), name.unwrap_or_default().as_bytes())
+}
+
+/// Position of the active configuration in `configurations`.
+pub fn find_active_config(configurations: &[Configuration]) -> Option<usize> {
+    let active = read_active_config()?;
+
+    configurations
+        .iter()
+        .position(|config| config.name == active)
+        // older versions stored the index into the configuration list instead of the name
+        .or_else(|| active.parse().ok().filter(|&index| index < configurations.len()))
+}
@@ -0,0 +1,365 @@
+use crate::configuration::{write_atomic, Configuration};
+use crate::migration;
+use crate::paths::*;
+use crate::validation;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{create_dir_all, read_dir, read_to_string};
+use std::path::PathBuf;
+
+// fields that hold numbers in a configuration but may be a variable in a template
+const NUMBER_FIELDS: [&str; 2] = ["port", "to_port"];
+
+/// Where a configuration came from, kept in it so template changes can be carried over.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TemplateRef {
+    pub name: String,
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+fn template_path(name: &str) -> anyhow::Result<PathBuf> {
+    // template names are file names under TEMPLATE_DIR just like configuration names
+    validation::validate_name(name, std::iter::empty())?;
+
+    Ok(PathBuf::from(format!("{}/{}.json", *TEMPLATE_DIR, name)))
+}
+
+/// Names of the templates in `TEMPLATE_DIR`, sorted.
+pub fn list() -> anyhow::Result<Vec<String>> {
+    create_dir_all(&*TEMPLATE_DIR)?;
+
+    let mut names: Vec<String> = read_dir(&*TEMPLATE_DIR)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("json"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    names.sort();
+
+    Ok(names)
+}
+
+/// A template is a configuration document in which strings may contain `${VARIABLE}`. `port` and
+/// `to_port` may be strings so they can be a variable too.
+pub fn load(name: &str) -> anyhow::Result<Value> {
+    let path = template_path(name)?;
+    let contents = read_to_string(&path).map_err(|e| anyhow::anyhow!("Failed to read template {}: {}", name, e))?;
+    let mut document: Value = serde_json::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("Template {} isn't valid JSON: {}", name, e))?;
+    migration::migrate(&mut document)?;
+
+    Ok(document)
+}
+
+/// Saves `document` as the template `name`, refusing to replace an existing one.
+pub fn save_new(name: &str, document: &Value) -> anyhow::Result<()> {
+    create_dir_all(&*TEMPLATE_DIR)?;
+    let path = template_path(name)?;
+    if path.exists() {
+        anyhow::bail!("a template named \"{}\" already exists", name);
+    }
+
+    write_atomic(&path, serde_json::to_string_pretty(document)?.as_bytes())
+}
+
+/// The variables `document` uses.
+pub fn variables(document: &Value) -> BTreeSet<String> {
+    let mut variables = BTreeSet::new();
+    visit_strings(document, &mut |text| {
+        let mut rest = text;
+        while let Some((_, variable, after)) = next_variable(rest) {
+            variables.insert(variable.to_string());
+            rest = after;
+        }
+    });
+
+    variables
+}
+
+/// Builds the configuration `name` from the template `reference` points to.
+pub fn instantiate(name: &str, reference: &TemplateRef) -> anyhow::Result<Configuration> {
+    build(name, reference, load(&reference.name)?)
+}
+
+fn build(name: &str, reference: &TemplateRef, mut document: Value) -> anyhow::Result<Configuration> {
+    let missing: Vec<String> = variables(&document)
+        .into_iter()
+        .filter(|variable| !reference.variables.contains_key(variable))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!("Template {} needs a value for {}", reference.name, missing.join(", "));
+    }
+
+    substitute(&mut document, &reference.variables);
+    numbers(&mut document);
+    if let Value::Object(object) = &mut document {
+        object.insert("name".to_string(), Value::String(name.to_string()));
+        object.remove("template");
+    }
+
+    let mut config: Configuration = serde_json::from_value(document)
+        .map_err(|e| anyhow::anyhow!("Template {} doesn't make a valid configuration: {}", reference.name, e))?;
+    config.template = Some(reference.clone());
+
+    Ok(config)
+}
+
+/// Makes a template out of `config`: the first proxy's host, port and credentials become
+/// `${HOST}`, `${PORT}`, `${USER}` and `${PASSWORD}` wherever they appear in the proxies.
+pub fn from_configuration(config: &Configuration) -> anyhow::Result<Value> {
+    let mut document = serde_json::to_value(config)?;
+    if let Value::Object(object) = &mut document {
+        object.retain(|key, _| key != "name" && key != "template");
+    }
+    let Some(first) = config.proxies.first() else {
+        return Ok(document);
+    };
+
+    if let Some(proxies) = document.get_mut("proxies").and_then(Value::as_array_mut) {
+        for proxy in proxies.iter_mut() {
+            let replacements = [
+                ("url", Value::String(first.url.clone()), "${HOST}"),
+                ("port", Value::from(first.port), "${PORT}"),
+                ("login", Value::String(first.login.clone()), "${USER}"),
+                ("password", Value::String(first.password.clone()), "${PASSWORD}"),
+            ];
+            for (field, value, variable) in replacements {
+                if value != Value::String(String::new()) && proxy.get(field) == Some(&value) {
+                    proxy[field] = Value::String(variable.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(document)
+}
+
+/// `config` with the proxies and rules its template makes now. Everything else, e.g. the
+/// schedule, how it is listed and keys this version doesn't know, is kept from `config`.
+pub fn rebuild(config: &Configuration) -> anyhow::Result<Configuration> {
+    let reference = config
+        .template
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("{} isn't made from a template", config.name))?;
+    let fresh = instantiate(&config.name, reference)?;
+
+    Ok(Configuration {
+        proxies: fresh.proxies,
+        rules: fresh.rules,
+        ..config.clone()
+    })
+}
+
+// the text before the first `${NAME}` in `text`, the name and the text after it
+fn next_variable(text: &str) -> Option<(&str, &str, &str)> {
+    let mut from = 0;
+    loop {
+        let start = from + text[from..].find("${")?;
+        let end = start + 2 + text[start + 2..].find('}')?;
+        let name = &text[start + 2..end];
+
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+            return Some((&text[..start], name, &text[end + 1..]));
+        }
+        from = start + 2;
+    }
+}
+
+fn visit_strings(value: &Value, visit: &mut impl FnMut(&str)) {
+    match value {
+        Value::String(text) => visit(text),
+        Value::Array(items) => items.iter().for_each(|item| visit_strings(item, visit)),
+        Value::Object(object) => object.values().for_each(|item| visit_strings(item, visit)),
+        _ => {}
+    }
+}
+
+fn substitute(value: &mut Value, variables: &BTreeMap<String, String>) {
+    match value {
+        Value::String(text) => {
+            // one pass, so a value containing ${OTHER} is copied as it is
+            let mut substituted = String::new();
+            let mut rest = text.as_str();
+            while let Some((before, name, after)) = next_variable(rest) {
+                substituted.push_str(before);
+                match variables.get(name) {
+                    Some(replacement) => substituted.push_str(replacement),
+                    None => substituted.push_str(&format!("${{{}}}", name)),
+                }
+                rest = after;
+            }
+            substituted.push_str(rest);
+            *text = substituted;
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| substitute(item, variables)),
+        Value::Object(object) => object.values_mut().for_each(|item| substitute(item, variables)),
+        _ => {}
+    }
+}
+
+// "1080" → 1080 for the port fields, anything else is left for deserializing to complain about
+fn numbers(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(numbers),
+        Value::Object(object) => {
+            for (key, item) in object.iter_mut() {
+                match item {
+                    Value::String(text) if NUMBER_FIELDS.contains(&key.as_str()) => {
+                        if let Ok(number) = text.trim().parse::<u64>() {
+                            *item = Value::from(number);
+                        }
+                    }
+                    _ => numbers(item),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reference(variables: &[(&str, &str)]) -> TemplateRef {
+        TemplateRef {
+            name: "office".to_string(),
+            variables: variables.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    fn document() -> Value {
+        json!({
+            "version": 1,
+            "proxies": [
+                { "proxy_type": "socks5", "url": "${HOST}", "port": "${PORT}", "login": "${USER}", "password": "${PASSWORD}" },
+                { "proxy_type": "http-connect", "url": "relay.${DOMAIN}", "port": 3128 },
+            ],
+            "rules": [{ "dport": "80,443", "to_port": "1488${N}", "action": "REDIRECT" }],
+        })
+    }
+
+    #[test]
+    fn variables_are_upper_case_names() {
+        let found = variables(&json!({
+            "a": "${HOST}:${PORT_2}",
+            "b": ["${lower} ${} $HOME ${UNCLOSED", { "c": "${x${USER}}" }],
+            "d": 1080,
+        }));
+        assert_eq!(found.into_iter().collect::<Vec<_>>(), ["HOST", "PORT_2", "USER"]);
+        assert!(variables(&json!({ "url": "plain" })).is_empty());
+    }
+
+    #[test]
+    fn substitute_copies_values_verbatim() {
+        let mut value = json!(["${PASSWORD}", "${USER}${USER}", "${USER}-${UNKNOWN}", "$USER"]);
+        substitute(&mut value, &reference(&[("PASSWORD", "${USER}x"), ("USER", "bob")]).variables);
+        assert_eq!(value, json!(["${USER}x", "bobbob", "bob-${UNKNOWN}", "$USER"]));
+    }
+
+    #[test]
+    fn numbers_only_converts_port_fields() {
+        let mut value = json!({
+            "proxies": [{ "port": " 1080 ", "url": "1080" }],
+            "rules": [{ "to_port": "14888", "dport": "80" }, { "to_port": "not a port" }],
+        });
+        numbers(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "proxies": [{ "port": 1080, "url": "1080" }],
+                "rules": [{ "to_port": 14888, "dport": "80" }, { "to_port": "not a port" }],
+            })
+        );
+    }
+
+    #[test]
+    fn instantiate_fills_in_variables() {
+        let reference = reference(&[
+            ("HOST", "10.0.0.1"),
+            ("PORT", "1080"),
+            ("USER", "bob"),
+            ("PASSWORD", "${DOMAIN}"),
+            ("DOMAIN", "example.com"),
+            ("N", "9"),
+        ]);
+        let config = build("home", &reference, document()).unwrap();
+
+        assert_eq!(config.name, "home");
+        assert_eq!(config.template, Some(reference));
+        assert_eq!(config.proxies[0].url, "10.0.0.1");
+        assert_eq!(config.proxies[0].port, 1080);
+        assert_eq!(config.proxies[0].login, "bob");
+        assert_eq!(config.proxies[0].password, "${DOMAIN}");
+        assert_eq!(config.proxies[1].url, "relay.example.com");
+        assert_eq!(config.rules[0].to_port, 14889);
+    }
+
+    #[test]
+    fn instantiate_reports_missing_and_invalid_values() {
+        let error = build("home", &reference(&[("HOST", "h")]), document()).unwrap_err();
+        assert_eq!(error.to_string(), "Template office needs a value for DOMAIN, N, PASSWORD, PORT, USER");
+
+        let reference = reference(&[
+            ("HOST", "h"),
+            ("PORT", "http"),
+            ("USER", ""),
+            ("PASSWORD", ""),
+            ("DOMAIN", "d"),
+            ("N", "0"),
+        ]);
+        assert!(build("home", &reference, document()).is_err());
+    }
+
+    #[test]
+    fn from_configuration_makes_variables_of_the_first_proxy() {
+        let config: Configuration = serde_json::from_value(json!({
+            "version": 1,
+            "name": "office",
+            "proxies": [
+                { "proxy_type": "socks5", "url": "10.0.0.1", "port": 1080, "login": "bob", "password": "secret" },
+                { "proxy_type": "socks5", "url": "10.0.0.2", "port": 1080 },
+            ],
+            "rules": [{ "dport": "1080", "to_port": 14888, "action": "REDIRECT" }],
+            "template": { "name": "old" },
+        }))
+        .unwrap();
+        let document = from_configuration(&config).unwrap();
+
+        assert!(document.get("name").is_none());
+        assert!(document.get("template").is_none());
+        assert_eq!(
+            document["proxies"],
+            json!([
+                { "proxy_type": "socks5", "url": "${HOST}", "port": "${PORT}", "login": "${USER}", "password": "${PASSWORD}" },
+                { "proxy_type": "socks5", "url": "10.0.0.2", "port": "${PORT}", "login": "", "password": "" },
+            ])
+        );
+        // only proxies are templated
+        assert_eq!(document["rules"][0]["dport"], "1080");
+
+        let reference = reference(&[("HOST", "10.0.0.1"), ("PORT", "1080"), ("USER", "bob"), ("PASSWORD", "secret")]);
+        let rebuilt = build("office", &reference, document).unwrap();
+        assert_eq!(
+            serde_json::to_value(&rebuilt.proxies).unwrap(),
+            serde_json::to_value(&config.proxies).unwrap()
+        );
+    }
+
+    #[test]
+    fn from_configuration_leaves_empty_credentials_alone() {
+        let config = Configuration::new(
+            "plain".to_string(),
+            serde_json::from_value(json!([{ "proxy_type": "http-connect", "url": "h", "port": 3128 }])).unwrap(),
+            Vec::new(),
+        );
+        let document = from_configuration(&config).unwrap();
+        assert_eq!(
+            document["proxies"],
+            json!([{ "proxy_type": "http-connect", "url": "${HOST}", "port": "${PORT}", "login": "", "password": "" }])
+        );
+    }
+}
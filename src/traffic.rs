@@ -0,0 +1,343 @@
+use crate::configuration::{Configuration, BASE_LOCAL_PORT};
+use crate::conntrack::{self, Connection};
+use crate::rpc::{ConfigurationTraffic, LiveConnection, Statistics};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// IPv4 and TCP headers with options, packets that weren't bigger carried no data
+const MAX_HEADER_BYTES: u64 = 60;
+// activations remembered to attribute connections that outlived them
+const MAX_ACTIVATIONS: usize = 16;
+// destinations counted one by one for each configuration, later ones are added up under OTHER
+const MAX_DESTINATIONS: usize = 1024;
+const OTHER_DESTINATIONS: &str = "other";
+
+/// Traffic through the redirects of the active configuration, from the kernel's connection
+/// tracking. Redirected connections are answered by redsocks on 127.0.0.1, and its port tells
+/// which proxy a connection went through.
+pub struct Accounting {
+    accounting: bool,
+    since: SystemTime,
+    /// The recent activations, oldest first. Every configuration listens on the same local
+    /// ports, so when a connection started tells which one it went through.
+    activations: VecDeque<Activation>,
+    /// Totals of the connections that ended
+    finished: BTreeMap<String, ConfigurationTraffic>,
+    /// Connections the last dump found, by conntrack id, so they are counted where they started
+    seen: HashMap<u32, Seen>,
+}
+
+struct Activation {
+    since: SystemTime,
+    /// Name of the configuration and its proxies as host:port, by local port, None if none was active
+    configuration: Option<(String, Vec<String>)>,
+}
+
+struct Seen {
+    configuration: String,
+    proxy: String,
+    first_seen: SystemTime,
+}
+
+impl Accounting {
+    /// `enable` turns the kernel's byte counters and timestamps on if they are off.
+    pub fn new(enable: bool) -> Accounting {
+        Accounting {
+            accounting: if enable { conntrack::enable_accounting() } else { conntrack::accounting_enabled() },
+            since: SystemTime::now(),
+            activations: VecDeque::new(),
+            finished: BTreeMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn set_active(&mut self, config: Option<&Configuration>) {
+        if self.activations.len() == MAX_ACTIVATIONS {
+            self.activations.pop_front();
+        }
+        // the configuration that is active when counting starts may have been for a while
+        let since = if self.activations.is_empty() { UNIX_EPOCH } else { SystemTime::now() };
+        self.activations.push_back(Activation {
+            since,
+            configuration: config.map(|config| {
+                let proxies = config.proxies.iter().map(|proxy| format!("{}:{}", proxy.url, proxy.port)).collect();
+                (config.name.clone(), proxies)
+            }),
+        });
+    }
+
+    // the configuration and proxy a connection went through, None if proxswap didn't redirect it
+    fn attribute(&self, connection: &Connection) -> Option<(String, String)> {
+        if let Some(seen) = self.seen.get(&connection.id) {
+            return Some((seen.configuration.clone(), seen.proxy.clone()));
+        }
+
+        // without start times (no accounting), the active configuration is the best guess
+        let activation = match connection.started {
+            Some(started) => self.activations.iter().rev().find(|activation| activation.since <= started)?,
+            None => self.activations.back()?,
+        };
+        let (name, proxies) = activation.configuration.as_ref()?;
+        if !connection.reply_source.ip().is_loopback() || connection.destination.ip().is_loopback() {
+            return None;
+        }
+        let index = connection.reply_source.port().checked_sub(BASE_LOCAL_PORT)? as usize;
+
+        Some((name.clone(), proxies.get(index)?.clone()))
+    }
+
+    /// Adds a connection that was removed from the conntrack table to the totals.
+    pub fn finished(&mut self, connection: &Connection) {
+        let Some((configuration, proxy)) = self.attribute(connection) else {
+            return;
+        };
+        self.seen.remove(&connection.id);
+
+        // nothing came back from the proxy, e.g. redsocks accepted and closed right away
+        let failed =
+            !connection.assured || (self.accounting && connection.bytes_down <= connection.packets_down * MAX_HEADER_BYTES);
+        let traffic = self.finished.entry(configuration.clone()).or_insert_with(|| ConfigurationTraffic {
+            name: configuration,
+            ..Default::default()
+        });
+        add(traffic, &proxy, connection, failed);
+    }
+
+    /// Totals of the connections that ended. Unlike `statistics`, they never go down.
+    pub fn totals(&self) -> Vec<ConfigurationTraffic> {
+        self.finished.values().cloned().collect()
+    }
+
+    /// The totals including the connections that are still open, and those connections.
+    /// `connections` is a `conntrack::dump()`, taken without holding up the accounting.
+    pub fn statistics(&mut self, connections: Vec<Connection>) -> Statistics {
+        let now = SystemTime::now();
+        let mut configurations = self.finished.clone();
+        let mut live = Vec::new();
+        let mut seen = HashMap::new();
+
+        for connection in connections {
+            let Some((configuration, proxy)) = self.attribute(&connection) else {
+                continue;
+            };
+            let first_seen = connection
+                .started
+                .or_else(|| self.seen.get(&connection.id).map(|seen| seen.first_seen))
+                .unwrap_or(now);
+
+            let traffic = configurations.entry(configuration.clone()).or_insert_with(|| ConfigurationTraffic {
+                name: configuration.clone(),
+                ..Default::default()
+            });
+            add(traffic, &proxy, &connection, false);
+
+            if connection.open {
+                live.push(LiveConnection {
+                    destination: connection.destination.to_string(),
+                    proxy: proxy.clone(),
+                    age_secs: now.duration_since(first_seen).unwrap_or_default().as_secs(),
+                    bytes_up: connection.bytes_up,
+                    bytes_down: connection.bytes_down,
+                });
+            }
+            seen.insert(connection.id, Seen { configuration, proxy, first_seen });
+        }
+        // forgets connections whose end was missed
+        self.seen = seen;
+
+        live.sort_by_key(|connection| connection.age_secs);
+        Statistics {
+            accounting: self.accounting,
+            since: self.since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            configurations: configurations.into_values().collect(),
+            connections: live,
+        }
+    }
+}
+
+fn add(traffic: &mut ConfigurationTraffic, proxy: &str, connection: &Connection, failed: bool) {
+    let mut destination = connection.destination.to_string();
+    if traffic.destinations.len() >= MAX_DESTINATIONS && !traffic.destinations.contains_key(&destination) {
+        destination = OTHER_DESTINATIONS.to_string();
+    }
+
+    let totals = [
+        &mut traffic.traffic,
+        traffic.proxies.entry(proxy.to_string()).or_default(),
+        traffic.destinations.entry(destination).or_default(),
+    ];
+
+    for total in totals {
+        total.connections += 1;
+        total.errors += failed as u64;
+        total.bytes_up += connection.bytes_up;
+        total.bytes_down += connection.bytes_down;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::SocketAddrV4;
+    use std::time::Duration;
+
+    fn accounting() -> Accounting {
+        Accounting {
+            accounting: true,
+            since: UNIX_EPOCH,
+            activations: VecDeque::new(),
+            finished: BTreeMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    fn config(name: &str) -> Configuration {
+        serde_json::from_value(json!({
+            "version": 1,
+            "name": name,
+            "proxies": [
+                { "proxy_type": "socks5", "url": "10.0.0.1", "port": 1080 },
+                { "proxy_type": "socks5", "url": "10.0.0.2", "port": 1081 },
+            ],
+            "rules": [],
+        }))
+        .unwrap()
+    }
+
+    fn connection(id: u32, reply_source: &str, destination: &str) -> Connection {
+        Connection {
+            id,
+            destination: destination.parse::<SocketAddrV4>().unwrap(),
+            reply_source: reply_source.parse::<SocketAddrV4>().unwrap(),
+            bytes_up: 100,
+            bytes_down: 5_000,
+            packets_down: 5,
+            assured: true,
+            open: false,
+            started: None,
+        }
+    }
+
+    fn pair(configuration: &str, proxy: &str) -> Option<(String, String)> {
+        Some((configuration.to_string(), proxy.to_string()))
+    }
+
+    #[test]
+    fn attributes_by_redsocks_port() {
+        let mut accounting = accounting();
+        let first = connection(1, "127.0.0.1:14888", "1.1.1.1:443");
+        assert_eq!(accounting.attribute(&first), None);
+
+        accounting.set_active(Some(&config("office")));
+        assert_eq!(accounting.attribute(&first), pair("office", "10.0.0.1:1080"));
+        assert_eq!(
+            accounting.attribute(&connection(2, "127.0.0.1:14889", "1.1.1.1:443")),
+            pair("office", "10.0.0.2:1081")
+        );
+        // not answered by redsocks, a port without a proxy, or local traffic
+        assert_eq!(accounting.attribute(&connection(3, "1.1.1.1:443", "1.1.1.1:443")), None);
+        assert_eq!(accounting.attribute(&connection(4, "127.0.0.1:14890", "1.1.1.1:443")), None);
+        assert_eq!(accounting.attribute(&connection(5, "127.0.0.1:8080", "1.1.1.1:443")), None);
+        assert_eq!(accounting.attribute(&connection(6, "127.0.0.1:14888", "127.0.0.1:14888")), None);
+
+        accounting.set_active(None);
+        assert_eq!(accounting.attribute(&first), None);
+    }
+
+    #[test]
+    fn attributes_by_start_time_after_a_switch() {
+        let mut accounting = accounting();
+        accounting.set_active(Some(&config("office")));
+        let switched = SystemTime::now();
+        accounting.set_active(Some(&config("home")));
+
+        let mut before = connection(1, "127.0.0.1:14888", "1.1.1.1:443");
+        before.started = Some(switched - Duration::from_secs(60));
+        assert_eq!(accounting.attribute(&before), pair("office", "10.0.0.1:1080"));
+
+        let mut after = connection(2, "127.0.0.1:14888", "1.1.1.1:443");
+        after.started = Some(SystemTime::now() + Duration::from_secs(1));
+        assert_eq!(accounting.attribute(&after), pair("home", "10.0.0.1:1080"));
+
+        // without a start time the newest activation is the best guess
+        assert_eq!(accounting.attribute(&connection(3, "127.0.0.1:14888", "1.1.1.1:443")), pair("home", "10.0.0.1:1080"));
+    }
+
+    #[test]
+    fn forgets_old_activations() {
+        let mut accounting = accounting();
+        for _ in 0..MAX_ACTIVATIONS + 5 {
+            accounting.set_active(Some(&config("office")));
+        }
+        assert_eq!(accounting.activations.len(), MAX_ACTIVATIONS);
+    }
+
+    #[test]
+    fn finished_connections_add_up() {
+        let mut accounting = accounting();
+        accounting.set_active(Some(&config("office")));
+
+        accounting.finished(&connection(1, "127.0.0.1:14888", "1.1.1.1:443"));
+        accounting.finished(&connection(2, "127.0.0.1:14889", "1.1.1.1:443"));
+        // nothing but headers came back
+        let mut empty = connection(3, "127.0.0.1:14888", "8.8.8.8:53");
+        empty.bytes_down = empty.packets_down * MAX_HEADER_BYTES;
+        accounting.finished(&empty);
+        let mut refused = connection(4, "127.0.0.1:14888", "8.8.8.8:53");
+        refused.assured = false;
+        accounting.finished(&refused);
+        // not redirected
+        accounting.finished(&connection(5, "10.0.0.9:443", "10.0.0.9:443"));
+
+        let totals = accounting.totals();
+        assert_eq!(totals.len(), 1);
+        let office = &totals[0];
+        assert_eq!(office.name, "office");
+        assert_eq!((office.traffic.connections, office.traffic.errors), (4, 2));
+        assert_eq!(office.traffic.bytes_up, 400);
+        assert_eq!(office.proxies["10.0.0.1:1080"].connections, 3);
+        assert_eq!(office.proxies["10.0.0.2:1081"].connections, 1);
+        assert_eq!(office.destinations["1.1.1.1:443"].connections, 2);
+        assert_eq!(office.destinations["8.8.8.8:53"].errors, 2);
+    }
+
+    #[test]
+    fn open_connections_count_where_they_were_first_seen() {
+        let mut accounting = accounting();
+        accounting.set_active(Some(&config("office")));
+
+        let mut open = connection(1, "127.0.0.1:14888", "1.1.1.1:443");
+        open.open = true;
+        let statistics = accounting.statistics(vec![open.clone()]);
+        assert_eq!(statistics.connections.len(), 1);
+        assert_eq!(statistics.connections[0].proxy, "10.0.0.1:1080");
+        assert_eq!(statistics.configurations[0].traffic.connections, 1);
+        // open connections aren't part of the totals
+        assert!(accounting.totals().is_empty());
+
+        // a switch doesn't move a connection that was already counted
+        accounting.set_active(Some(&config("home")));
+        accounting.finished(&open);
+        assert_eq!(accounting.totals()[0].name, "office");
+        assert!(accounting.seen.is_empty());
+    }
+
+    #[test]
+    fn destinations_are_capped() {
+        let mut accounting = accounting();
+        accounting.set_active(Some(&config("office")));
+        for i in 0..MAX_DESTINATIONS as u32 + 10 {
+            let destination = SocketAddrV4::new(i.into(), 443).to_string();
+            accounting.finished(&connection(i, "127.0.0.1:14888", &destination));
+        }
+        // an address that is counted already keeps being counted on its own
+        accounting.finished(&connection(0, "127.0.0.1:14888", "0.0.0.0:443"));
+
+        let office = &accounting.totals()[0];
+        assert_eq!(office.destinations.len(), MAX_DESTINATIONS + 1);
+        assert_eq!(office.destinations[OTHER_DESTINATIONS].connections, 10);
+        assert_eq!(office.destinations["0.0.0.0:443"].connections, 2);
+        assert_eq!(office.traffic.connections, MAX_DESTINATIONS as u64 + 11);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::configuration::Configuration;
+use crate::loader;
+use crate::paths::*;
+use crate::validation::{self, ValidationError};
+use std::fs::{create_dir_all, hard_link, read_dir, remove_file, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A deleted configuration, kept in `TRASH_DIR` as `<milliseconds since the epoch>-<name>.json`.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub deleted: SystemTime,
+    pub path: PathBuf,
+}
+
+impl Entry {
+    fn from_path(path: PathBuf) -> Option<Entry> {
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        let (millis, name) = stem.split_once('-')?;
+        let deleted = UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?);
+
+        Some(Entry {
+            name: name.to_string(),
+            deleted,
+            path,
+        })
+    }
+}
+
+/// Moves the configuration's file into the trash. Its redsocks config is generated on every
+/// activation, so it is removed instead.
+///
+/// The loader only accepts files named after the configuration, so `config_path()` is the file
+/// it was loaded from.
+pub fn move_to_trash(config: &Configuration) -> anyhow::Result<Entry> {
+    validation::validate_name(&config.name, std::iter::empty())?;
+    create_dir_all(&*TRASH_DIR)?;
+
+    let deleted = SystemTime::now();
+    let millis = deleted.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let path = PathBuf::from(format!("{}/{}-{}.json", *TRASH_DIR, millis, config.name));
+    rename(config.config_path(), &path)
+        .map_err(|e| anyhow::anyhow!("Failed to move {} to the trash: {}", config.config_path().display(), e))?;
+    let _ = remove_file(config.redsocks_config_path());
+
+    Ok(Entry {
+        name: config.name.clone(),
+        deleted,
+        path,
+    })
+}
+
+/// The configurations in the trash, most recently deleted first.
+pub fn list() -> io::Result<Vec<Entry>> {
+    let entries = match read_dir(&*TRASH_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries: Vec<Entry> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| Entry::from_path(entry.path()))
+        .collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.deleted));
+
+    Ok(entries)
+}
+
+/// The most recently deleted configuration called `name`.
+pub fn find(name: &str) -> anyhow::Result<Entry> {
+    list()?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| anyhow::anyhow!("No configuration named \"{}\" in the trash", name))
+}
+
+/// Moves `entry` back to `CONFIG_DIR`. A configuration that took its name in the meantime isn't
+/// replaced.
+pub async fn restore(entry: &Entry) -> anyhow::Result<Configuration> {
+    validation::validate_name(&entry.name, std::iter::empty())?;
+    let path = PathBuf::from(format!("{}/{}.json", *CONFIG_DIR, entry.name));
+
+    // a link fails instead of replacing a file created in the meantime, unlike a rename
+    match hard_link(&entry.path, &path) {
+        Ok(()) => remove_file(&entry.path)?,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(ValidationError::DuplicateName(entry.name.clone()).into())
+        }
+        Err(e) => return Err(e.into()),
+    }
+    loader::load_configuration(Path::new(&path))
+        .await
+        .map_err(|e| anyhow::anyhow!("Restored {} but it doesn't load: {}", entry.name, e))
+}
+
+/// Deletes everything in the trash for good, returns how many configurations that was.
+pub fn empty() -> io::Result<usize> {
+    let entries = list()?;
+    for entry in entries.iter() {
+        remove_file(&entry.path)?;
+    }
+
+    Ok(entries.len())
+}
@@ -1,36 +1,98 @@
-use crate::{configuration::{Configuration, IptablesRule, Proxy}, CONFIG_DIR};
+use crate::{configuration::{Configuration, IptablesRule, Ports, Proxy, ProxyKind, RuleAction, BASE_LOCAL_PORT}, AUDIT_LOG_PATH, EXPORT_DIR};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor::Show,
+    event::{DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture, Event, EventStream, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Clear},
+    widgets::{Block, Borders, Cell, List, ListItem, ListState, Paragraph, Clear, Row, Table, Wrap},
     style::Color,
 };
-use std::{error::Error, io::{self, Write}, process::Command};
+use std::{collections::{BTreeMap, HashMap, HashSet}, error::Error, io::{self, IsTerminal, Write}, path::Path, process::Command, time::{Duration, Instant, SystemTime}};
+use crate::audit::{self, Action, Record};
 use crate::bindings;
+use crate::privileges::{self, Backend as RulesBackend};
+use crate::client::Client;
+use crate::rpc::{Event as DaemonEvent, ProxyStatus, Statistics};
+use crate::schedule::{self, Schedule, Window};
+use crate::search::{Field, Match, Query};
+use crate::settings::ExitPolicy;
+use crate::state;
+use crate::trash;
+use crate::export::{self, ExportFormat};
+use crate::uri;
+use serde_json::Map;
+use crate::loader::{self, LoadError};
+use crate::validation::{self, ValidationError};
+use tokio::signal::unix::{signal, SignalKind};
+use futures::StreamExt;
+use tokio::sync::mpsc;
+
+// how often the connections pane asks proxswapd for new numbers
+const STATISTICS_INTERVAL: Duration = Duration::from_secs(2);
 
 pub enum InputMode {
     Normal,
     Editing,
     Creating,
+    Exporting,
+    ConfirmExit,
+    ConfirmDelete,
+    Trash,
+    Duplicating,
+}
+
+/// Why the event loop ended.
+enum Exit {
+    Quit,
+    Signal(SignalKind),
+}
+
+/// What woke the event loop up.
+enum Wake {
+    Signal(SignalKind),
+    Daemon(DaemonEvent),
+    Statistics,
+    Terminal(Event),
+}
+
+// the next message of a channel that may not be there, which never comes then
+async fn next<T>(receiver: &mut Option<mpsc::UnboundedReceiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A row of the Configurations pane, the broken files follow them.
+#[derive(Clone, PartialEq)]
+enum ListEntry {
+    /// Header of a group with how many of the listed configurations are in it
+    Group { name: String, count: usize },
+    Config(usize),
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum Focus {
     ConfigList,
     ProxyList,
     RulesList,
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum CreationField {
     Name,
+    ProxyUri,
     ProxyType,
     ProxyUrl,
     ProxyPort,
     ProxyLogin,
     ProxyPassword,
+    AddProxy,
+    RuleProxy,
+    RuleAction,
     RedirectPorts,
     Confirm,
 }
@@ -38,13 +100,18 @@ pub enum CreationField {
 pub struct CreationState {
     current_field: CreationField,
     name: String,
+    proxy_uri: String,
     proxy_type: String,
     proxy_url: String,
     proxy_port: String,
     proxy_login: String,
     proxy_password: String,
-    redirect_ports: Vec<String>,  
+    proxies: Vec<Proxy>,
+    rule_proxy: usize,
+    rule_action: RuleAction,
+    rules: Vec<IptablesRule>,
     current_port_input: String,   
+    save_error: Option<String>,
 }
 
 impl CreationState {
@@ -52,24 +119,33 @@ impl CreationState {
         CreationState {
             current_field: CreationField::Name,
             name: String::new(),
+            proxy_uri: String::new(),
             proxy_type: String::new(),
             proxy_url: String::new(),
             proxy_port: String::new(),
             proxy_login: String::new(),
             proxy_password: String::new(),
-            redirect_ports: Vec::new(),
+            proxies: Vec::new(),
+            rule_proxy: 0,
+            rule_action: RuleAction::Redirect,
+            rules: Vec::new(),
             current_port_input: String::new(),
+            save_error: None,
         }
     }
 
     fn next_field(&mut self) {
         self.current_field = match self.current_field {
-            CreationField::Name => CreationField::ProxyType,
+            CreationField::Name => CreationField::ProxyUri,
+            CreationField::ProxyUri => CreationField::ProxyType,
             CreationField::ProxyType => CreationField::ProxyUrl,
             CreationField::ProxyUrl => CreationField::ProxyPort,
             CreationField::ProxyPort => CreationField::ProxyLogin,
             CreationField::ProxyLogin => CreationField::ProxyPassword,
-            CreationField::ProxyPassword => CreationField::RedirectPorts,
+            CreationField::ProxyPassword => CreationField::AddProxy,
+            CreationField::AddProxy => CreationField::RuleProxy,
+            CreationField::RuleProxy => CreationField::RuleAction,
+            CreationField::RuleAction => CreationField::RedirectPorts,
             CreationField::RedirectPorts => CreationField::Confirm,
             CreationField::Confirm => CreationField::Confirm,
         };
@@ -78,15 +154,183 @@ impl CreationState {
     fn previous_field(&mut self) {
         self.current_field = match self.current_field {
             CreationField::Name => CreationField::Name,
-            CreationField::ProxyType => CreationField::Name,
+            CreationField::ProxyUri => CreationField::Name,
+            CreationField::ProxyType => CreationField::ProxyUri,
             CreationField::ProxyUrl => CreationField::ProxyType,
             CreationField::ProxyPort => CreationField::ProxyUrl,
             CreationField::ProxyLogin => CreationField::ProxyPort,
             CreationField::ProxyPassword => CreationField::ProxyLogin,
-            CreationField::RedirectPorts => CreationField::ProxyPassword,
+            CreationField::AddProxy => CreationField::ProxyPassword,
+            CreationField::RuleProxy => CreationField::AddProxy,
+            CreationField::RuleAction => CreationField::RuleProxy,
+            CreationField::RedirectPorts => CreationField::RuleAction,
             CreationField::Confirm => CreationField::RedirectPorts,
         };
     }
+
+    fn push_char(&mut self, c: char) {
+        match self.current_field {
+            CreationField::Name => self.name.push(c),
+            CreationField::ProxyUri => self.proxy_uri.push(c),
+            CreationField::ProxyType => self.proxy_type.push(c),
+            CreationField::ProxyUrl => self.proxy_url.push(c),
+            CreationField::ProxyPort => {
+                if c.is_ascii_digit() {
+                    self.proxy_port.push(c);
+                }
+            }
+            CreationField::ProxyLogin => self.proxy_login.push(c),
+            CreationField::ProxyPassword => self.proxy_password.push(c),
+            CreationField::RedirectPorts => {
+                if (c.is_ascii_digit()) || (c == ':') || (c == ',') {
+                    self.current_port_input.push(c);
+                }
+            }
+            CreationField::AddProxy
+            | CreationField::RuleProxy
+            | CreationField::RuleAction
+            | CreationField::Confirm => {}
+        }
+    }
+
+    // fills the proxy fields from a pasted URI so it can be reviewed before it's added
+    fn apply_proxy_uri(&mut self) {
+        let Ok(proxy) = uri::parse_proxy_uri(&self.proxy_uri) else {
+            return;
+        };
+
+        self.proxy_type = proxy.proxy_type.to_string();
+        self.proxy_url = proxy.url;
+        self.proxy_port = proxy.port.to_string();
+        self.proxy_login = proxy.login;
+        self.proxy_password = proxy.password;
+        self.proxy_uri.clear();
+        self.current_field = CreationField::AddProxy;
+    }
+
+    fn has_proxy_draft(&self) -> bool {
+        !(self.proxy_type.is_empty()
+            && self.proxy_url.is_empty()
+            && self.proxy_port.is_empty()
+            && self.proxy_login.is_empty()
+            && self.proxy_password.is_empty())
+    }
+
+    // proxies a rule may point at, counting the one still being typed in
+    fn proxy_count(&self) -> usize {
+        (self.proxies.len() + self.has_proxy_draft() as usize).max(1)
+    }
+
+    fn draft_proxy(&self) -> Result<Proxy, ValidationError> {
+        let proxy = Proxy {
+            proxy_type: self.proxy_type.parse()?,
+            url: self.proxy_url.clone(),
+            port: validation::parse_port(&self.proxy_port)?,
+            login: self.proxy_login.clone(),
+            password: self.proxy_password.clone(),
+            extra: Map::new(),
+        };
+        proxy.validate()?;
+
+        Ok(proxy)
+    }
+
+    fn field_error(&self, field: CreationField, configurations: &[Configuration]) -> Option<ValidationError> {
+        let draft = self.has_proxy_draft();
+
+        match field {
+            CreationField::Name => validation::validate_name(
+                &self.name,
+                configurations.iter().map(|config| config.name.as_str()),
+            ).err(),
+            CreationField::ProxyUri if !self.proxy_uri.is_empty() => uri::parse_proxy_uri(&self.proxy_uri)
+                .err()
+                .map(ValidationError::InvalidProxyUri),
+            CreationField::ProxyType if draft => self.proxy_type.parse::<ProxyKind>().err(),
+            CreationField::ProxyUrl if draft => validation::validate_host(&self.proxy_url).err(),
+            CreationField::ProxyPort if draft => validation::parse_port(&self.proxy_port).err(),
+            CreationField::ProxyPassword if draft => {
+                validation::validate_credentials(&self.proxy_login, &self.proxy_password).err()
+            }
+            CreationField::AddProxy if !draft && self.proxies.is_empty() => Some(ValidationError::NoProxies),
+            CreationField::RedirectPorts if !self.current_port_input.is_empty() => {
+                self.current_port_input.parse::<Ports>().err()
+            }
+            _ => None,
+        }
+    }
+
+    fn is_valid(&self, configurations: &[Configuration]) -> bool {
+        [
+            CreationField::Name,
+            CreationField::ProxyType,
+            CreationField::ProxyUrl,
+            CreationField::ProxyPort,
+            CreationField::ProxyPassword,
+            CreationField::AddProxy,
+            CreationField::RedirectPorts,
+        ]
+        .into_iter()
+        .all(|field| self.field_error(field, configurations).is_none())
+    }
+
+    fn push_proxy(&mut self) -> bool {
+        let Ok(proxy) = self.draft_proxy() else {
+            return false;
+        };
+
+        self.proxies.push(proxy);
+        self.proxy_type.clear();
+        self.proxy_url.clear();
+        self.proxy_port.clear();
+        self.proxy_login.clear();
+        self.proxy_password.clear();
+
+        true
+    }
+
+    fn pop_proxy(&mut self) {
+        if self.proxies.pop().is_some() {
+            let local_port_limit = BASE_LOCAL_PORT + self.proxy_count() as u16;
+            self.rules.retain(|rule| rule.to_port < local_port_limit);
+            self.rule_proxy = self.rule_proxy.min(self.proxy_count() - 1);
+        }
+    }
+
+    fn push_rule(&mut self) {
+        let Ok(dport) = self.current_port_input.parse::<Ports>() else {
+            return;
+        };
+
+        self.rules.push(IptablesRule {
+            dport,
+            destination: None,
+            to_port: BASE_LOCAL_PORT + self.rule_proxy as u16,
+            action: self.rule_action,
+            extra: Map::new(),
+        });
+        self.current_port_input.clear();
+    }
+
+    fn cycle_rule_proxy(&mut self, forward: bool) {
+        let count = self.proxy_count();
+        self.rule_proxy = if forward {
+            (self.rule_proxy + 1) % count
+        } else {
+            (self.rule_proxy + count - 1) % count
+        };
+    }
+
+    fn cycle_rule_action(&mut self, forward: bool) {
+        let count = RuleAction::ALL.len();
+        let current = RuleAction::ALL.iter().position(|&action| action == self.rule_action).unwrap_or(0);
+        let next = if forward {
+            (current + 1) % count
+        } else {
+            (current + count - 1) % count
+        };
+        self.rule_action = RuleAction::ALL[next];
+    }
 }
 
 pub struct App {
@@ -96,97 +340,317 @@ pub struct App {
     input_mode: InputMode,
     focus: Focus,
     search_query: String,
-    filtered_configs: Vec<usize>, 
+    list_entries: Vec<ListEntry>,
+    // groups whose configurations are hidden
+    collapsed: HashSet<String>,
+    // by configuration index, while searching
+    search_matches: HashMap<usize, Match>,
     creation_state: Option<CreationState>,
+    load_errors: Vec<LoadError>,
+    export_list_state: ListState,
+    status_message: Option<String>,
+    daemon: Option<Client>,
+    daemon_events: Option<mpsc::UnboundedReceiver<DaemonEvent>>,
+    proxy_health: Vec<ProxyStatus>,
+    exit_policy: ExitPolicy,
+    signals: Option<mpsc::UnboundedReceiver<SignalKind>>,
+    // shown instead of the proxies and rules while set
+    statistics: Option<Result<Statistics, String>>,
+    statistics_refreshed: Instant,
+    // like statistics, newest record first
+    history: Option<Result<Vec<Record>, String>>,
+    // most recently deleted first, read when the trash dialog opens
+    trash: Vec<trash::Entry>,
+    trash_list_state: ListState,
+    // name typed into the duplicate dialog and why saving failed
+    duplicate_name: String,
+    duplicate_error: Option<String>,
 }
 
 impl App {
-    pub fn new(configurations: Vec<Configuration>) -> Self {
-        let filtered_configs: Vec<usize> = (0..configurations.len()).collect();
-        App {
+    pub fn new(configurations: Vec<Configuration>, load_errors: Vec<LoadError>, exit_policy: ExitPolicy) -> Self {
+        let active_config_index = state::find_active_config(&configurations);
+        let mut app = App {
             configurations,
-            active_config_index: dbg!(Self::get_active_config()),
+            load_errors,
+            active_config_index,
             config_list_state: ListState::default(),
             input_mode: InputMode::Normal,
             focus: Focus::ConfigList,
             search_query: String::new(),
-            filtered_configs,
+            list_entries: Vec::new(),
+            collapsed: HashSet::new(),
+            search_matches: HashMap::new(),
             creation_state: None,
-        }
+            export_list_state: ListState::default(),
+            status_message: None,
+            daemon: None,
+            daemon_events: None,
+            proxy_health: Vec::new(),
+            exit_policy,
+            signals: None,
+            statistics: None,
+            statistics_refreshed: Instant::now(),
+            history: None,
+            trash: Vec::new(),
+            trash_list_state: ListState::default(),
+            duplicate_name: String::new(),
+            duplicate_error: None,
+        };
+        app.filter_configurations();
+        app
     }
 
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        self.ensure_sudo_access().await?;
+        self.ensure_privileges().await?;
+        self.connect_daemon().await;
+
+        self.listen_for_signals()?;
+
+        // a panic would otherwise leave the shell in raw mode on the alternate screen
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            default_hook(info);
+        }));
 
         enable_raw_mode()?;
         let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
 
-        let _res = self.run_app(&mut terminal).await;
+        let exit = self.run_app(&mut terminal).await;
 
-        disable_raw_mode()?;
-        execute!(
-            terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        terminal.show_cursor()?;
+        restore_terminal();
+
+        // nobody may be there to answer after a hangup
+        if let Exit::Signal(signal) = exit? {
+            let can_ask = signal != SignalKind::hangup();
+            apply_exit_policy(self.exit_policy, can_ask).await;
+        }
 
         Ok(())
     }
 
-    fn get_active_config() -> Option<usize> {
-        let path = format!("{}/active_config.txt", *CONFIG_DIR);
-   
-        if !std::path::Path::new(&path).exists() {
-            return None
+    // raw mode turns Ctrl-C into a key press, these come from kill, systemd or a closed terminal
+    fn listen_for_signals(&mut self) -> io::Result<()> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        for kind in [SignalKind::interrupt(), SignalKind::terminate(), SignalKind::hangup()] {
+            let mut signals = signal(kind)?;
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if signals.recv().await.is_some() {
+                    let _ = sender.send(kind);
+                }
+            });
         }
 
-        let val = std::fs::read_to_string(&path).ok()?;
-        let trimmed = val.trim();
+        self.signals = Some(receiver);
+        Ok(())
+    }
 
-        if trimmed == "None" {
-            None
-        } else {
-            trimmed.parse().ok()
+    // finds out up front how rules will be applied, instead of failing on the first one
+    async fn ensure_privileges(&self) -> Result<(), Box<dyn Error>> {
+        if privileges::backend().await != RulesBackend::Sudo {
+            return Ok(());
         }
-    }
 
-    async fn ensure_sudo_access(&self) -> Result<(), Box<dyn Error>> {
-        let status = Command::new("sudo")
+        let sudo = Command::new("sudo")
             .arg("-v")
-            .status()?;
+            .status();
 
-        if !status.success() {
-            return Err("Failed to obtain sudo privileges".into());
+        if !sudo.is_ok_and(|status| status.success()) {
+            return Err(privileges::MISSING_PRIVILEGES.into());
         }
         Ok(())
     }
 
-    async fn write_active_config(&self, index: String) {
-        let mut file = std::fs::File::create(format!("{}/active_config.txt", *CONFIG_DIR)).unwrap();
-        let _ = file.write_all(index.as_bytes());
+    // with proxswapd running the TUI is one of its clients, otherwise it activates configurations itself
+    async fn connect_daemon(&mut self) {
+        let Ok(mut daemon) = Client::connect().await else {
+            return;
+        };
+
+        if let Ok(status) = daemon.status().await {
+            self.active_config_index = status
+                .active
+                .and_then(|name| self.configurations.iter().position(|config| config.name == name));
+            self.proxy_health = status.proxies;
+        }
+
+        // events get their own connection, so they can be read while the TUI waits for keys
+        if let Ok(mut events) = Client::connect().await {
+            if events.subscribe().await.is_ok() {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    while let Ok(event) = events.next_event().await {
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                });
+                self.daemon_events = Some(receiver);
+            }
+        }
+
+        self.daemon = Some(daemon);
+    }
+
+    fn apply_daemon_event(&mut self, event: DaemonEvent) {
+        match event {
+            DaemonEvent::Activated { name } => {
+                self.active_config_index = self.configurations.iter().position(|config| config.name == name);
+                self.proxy_health.clear();
+            }
+            DaemonEvent::Deactivated => {
+                self.active_config_index = None;
+                self.proxy_health.clear();
+            }
+            DaemonEvent::Health { name, proxies } => {
+                if self.active_config_index.map(|index| &self.configurations[index].name) == Some(&name) {
+                    self.proxy_health = proxies;
+                }
+            }
+        }
+    }
+
+    async fn refresh_statistics(&mut self) {
+        self.statistics_refreshed = Instant::now();
+        self.statistics = Some(match &mut self.daemon {
+            Some(daemon) => daemon.stats().await.map_err(|e| e.to_string()),
+            None => Err("Traffic statistics come from proxswapd, start it with `proxswap daemon`".to_string()),
+        });
+    }
+
+    fn load_history(&mut self) {
+        self.statistics = None;
+        self.history = Some(
+            audit::read()
+                .map(|mut records| {
+                    records.reverse();
+                    records
+                })
+                .map_err(|e| format!("Failed to read {}: {}", *AUDIT_LOG_PATH, e)),
+        );
+    }
+
+    // proxswapd keeps the history of what it applies, this is for what the TUI does itself
+    fn log(&mut self, record: Record) {
+        if let Err(e) = record.append() {
+            self.status_message = Some(format!("│ Failed to write the history: {}", e));
+        }
+    }
+
+    async fn activate_config(&mut self, real_index: usize) {
+        let Some(daemon) = &mut self.daemon else {
+            let result = self.configurations[real_index].run().await;
+            let record = Record::new(&audit::current_user(), Action::Activate)
+                .configuration(&self.configurations[real_index])
+                .outcome(&result);
+            self.log(record);
+            match result {
+                Ok(()) => self.set_active_config(Some(real_index)).await,
+                Err(e) => {
+                    // the previous configuration may be half torn down already, don't claim it is active
+                    self.set_active_config(None).await;
+                    self.status_message = Some(format!("│ Activation failed: {}", e));
+                }
+            }
+            return;
+        };
+
+        match daemon.activate(&self.configurations[real_index].name).await {
+            Ok(()) => {
+                self.active_config_index = Some(real_index);
+                self.proxy_health.clear();
+            }
+            Err(e) => self.status_message = Some(format!("│ Activation failed: {}", e)),
+        }
     }
 
     async fn set_active_config(&mut self, real_index: Option<usize>) {
-        match real_index {
-            Some(i) => self.write_active_config(i.to_string()).await,
-            None => self.write_active_config("None".to_string()).await,
+        let name = real_index.map(|i| self.configurations[i].name.as_str());
+        if let Err(e) = state::write_active_config(name) {
+            self.status_message = Some(format!("│ Failed to remember the active configuration: {}", e));
         }
         self.active_config_index = real_index;
     }
 
-    async fn run_app<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+    async fn run_app<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<Exit> {
+        // read asynchronously instead of blocking in event::read, the runtime may have a single
+        // worker and proxswapd's events and signals are handled by tasks on it
+        let mut terminal_events = EventStream::new();
+
         loop {
             terminal.draw(|f| self.ui(f))?;
 
-            if let Event::Key(key) = event::read()? {
+            let refresh = tokio::time::Instant::from_std(self.statistics_refreshed + STATISTICS_INTERVAL);
+            let woken = tokio::select! {
+                Some(signal) = next(&mut self.signals) => Wake::Signal(signal),
+                Some(event) = next(&mut self.daemon_events) => Wake::Daemon(event),
+                _ = tokio::time::sleep_until(refresh), if self.statistics.is_some() => Wake::Statistics,
+                event = terminal_events.next() => match event {
+                    Some(event) => Wake::Terminal(event?),
+                    // stdin is gone, nobody is left to use the TUI
+                    None => return Ok(Exit::Quit),
+                },
+            };
+            let event = match woken {
+                Wake::Signal(signal) => return Ok(Exit::Signal(signal)),
+                Wake::Daemon(event) => {
+                    self.apply_daemon_event(event);
+                    continue;
+                }
+                Wake::Statistics => {
+                    self.refresh_statistics().await;
+                    continue;
+                }
+                Wake::Terminal(event) => event,
+            };
+
+            if let Event::Paste(text) = &event {
+                match self.input_mode {
+                    InputMode::Creating => {
+                        if let Some(creation_state) = &mut self.creation_state {
+                            text.trim().chars().for_each(|c| creation_state.push_char(c));
+                        }
+                    }
+                    InputMode::Editing => {
+                        self.search_query.push_str(text.trim());
+                        self.filter_configurations();
+                    }
+                    InputMode::Duplicating => self.duplicate_name.push_str(text.trim()),
+                    InputMode::Normal
+                    | InputMode::Exporting
+                    | InputMode::ConfirmExit
+                    | InputMode::ConfirmDelete
+                    | InputMode::Trash => {}
+                }
+            }
+
+            if let Event::Key(key) = event {
+                self.status_message = None;
+
                 match self.input_mode {
                     InputMode::Normal => {
                         match key.code {
-                            KeyCode::Char('q') => return Ok(()),
+                            KeyCode::Char('q') => {
+                                // with proxswapd running the active configuration is the daemon's business
+                                if self.active_config_index.is_none() || self.daemon.is_some() {
+                                    return Ok(Exit::Quit);
+                                }
+
+                                match self.exit_policy {
+                                    ExitPolicy::Keep => return Ok(Exit::Quit),
+                                    ExitPolicy::Deactivate => {
+                                        self.deactivate_proxy().await;
+                                        return Ok(Exit::Quit);
+                                    }
+                                    ExitPolicy::Ask => self.input_mode = InputMode::ConfirmExit,
+                                }
+                            }
                             KeyCode::Char('e') => self.input_mode = InputMode::Editing,
                             KeyCode::Char('c') => {
                                 self.input_mode = InputMode::Creating;
@@ -195,6 +659,13 @@ impl App {
                             KeyCode::Char('x') => {
                                 self.deactivate_proxy().await;
                             }
+                            KeyCode::Char('t') if self.statistics.is_some() => self.statistics = None,
+                            KeyCode::Char('t') => {
+                                self.history = None;
+                                self.refresh_statistics().await;
+                            }
+                            KeyCode::Char('h') if self.history.is_some() => self.history = None,
+                            KeyCode::Char('h') => self.load_history(),
                             KeyCode::Char('/') => {
                                 self.search_query.clear();
                                 self.input_mode = InputMode::Editing;
@@ -202,18 +673,107 @@ impl App {
                             KeyCode::Down => self.next(),
                             KeyCode::Up => self.previous(),
                             KeyCode::Enter => {
-                                if let Some(index) = self.config_list_state.selected() {
-                                    if let Some(&real_index) = self.filtered_configs.get(index) {
-                                        self.set_active_config(Some(real_index)).await;
-                                        self.configurations[real_index].run().await;
-                                    }
+                                let selected = self.config_list_state.selected();
+                                match selected.and_then(|selected| self.list_entries.get(selected)).cloned() {
+                                    Some(ListEntry::Config(real_index)) => self.activate_config(real_index).await,
+                                    Some(ListEntry::Group { name, .. }) => self.toggle_group(&name),
+                                    None => {}
+                                }
+                            }
+                            KeyCode::Char('f') if self.selected_config_index().is_some() => self.toggle_favourite(),
+                            KeyCode::Char('d') if self.selected_config_index().is_some() => {
+                                self.input_mode = InputMode::ConfirmDelete;
+                            }
+                            KeyCode::Char('u') => self.undo_delete().await,
+                            KeyCode::Char('T') => self.open_trash(),
+                            KeyCode::Char('D') => {
+                                if let Some(index) = self.selected_config_index() {
+                                    self.duplicate_name = format!("{}-copy", self.configurations[index].name);
+                                    self.duplicate_error = None;
+                                    self.input_mode = InputMode::Duplicating;
                                 }
                             }
-                            KeyCode::Char('d') => self.delete_selected(),
+                            KeyCode::Char('o') => self.open_broken_in_editor(terminal).await?,
+                            KeyCode::Char('E') if self.selected_config_index().is_some() => {
+                                self.export_list_state.select(Some(0));
+                                self.input_mode = InputMode::Exporting;
+                            }
                             KeyCode::Tab => self.cycle_focus(),
                             _ => {}
                         }
                     }
+                    InputMode::ConfirmExit => {
+                        match key.code {
+                            KeyCode::Char('y') => {
+                                self.deactivate_proxy().await;
+                                return Ok(Exit::Quit);
+                            }
+                            KeyCode::Char('n') => return Ok(Exit::Quit),
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            _ => {}
+                        }
+                    }
+                    InputMode::ConfirmDelete => {
+                        match key.code {
+                            KeyCode::Char('y') => {
+                                self.input_mode = InputMode::Normal;
+                                self.delete_selected().await;
+                            }
+                            KeyCode::Char('n') | KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            _ => {}
+                        }
+                    }
+                    InputMode::Duplicating => {
+                        match key.code {
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            KeyCode::Enter if self.duplicate_selected() => self.input_mode = InputMode::Normal,
+                            KeyCode::Char(c) => self.duplicate_name.push(c),
+                            KeyCode::Backspace => {
+                                self.duplicate_name.pop();
+                            }
+                            _ => {}
+                        }
+                    }
+                    InputMode::Trash => {
+                        match key.code {
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            KeyCode::Down => {
+                                let i = self.trash_list_state.selected().unwrap_or(0);
+                                self.trash_list_state.select(Some((i + 1) % self.trash.len()));
+                            }
+                            KeyCode::Up => {
+                                let i = self.trash_list_state.selected().unwrap_or(0);
+                                let count = self.trash.len();
+                                self.trash_list_state.select(Some((i + count - 1) % count));
+                            }
+                            KeyCode::Enter => {
+                                let entry = self.trash[self.trash_list_state.selected().unwrap_or(0)].clone();
+                                self.input_mode = InputMode::Normal;
+                                self.restore_from_trash(&entry).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                    InputMode::Exporting => {
+                        match key.code {
+                            KeyCode::Esc => self.input_mode = InputMode::Normal,
+                            KeyCode::Down => {
+                                let i = self.export_list_state.selected().unwrap_or(0);
+                                self.export_list_state.select(Some((i + 1) % ExportFormat::ALL.len()));
+                            }
+                            KeyCode::Up => {
+                                let i = self.export_list_state.selected().unwrap_or(0);
+                                let count = ExportFormat::ALL.len();
+                                self.export_list_state.select(Some((i + count - 1) % count));
+                            }
+                            KeyCode::Enter => {
+                                let format = ExportFormat::ALL[self.export_list_state.selected().unwrap_or(0)];
+                                self.export_selected(format);
+                                self.input_mode = InputMode::Normal;
+                            }
+                            _ => {}
+                        }
+                    }
                     InputMode::Editing => {
                         match key.code {
                             KeyCode::Enter => {
@@ -252,21 +812,35 @@ impl App {
                                     creation_state.previous_field();
                                 }
                             }
+                            KeyCode::Left | KeyCode::Right => {
+                                if let Some(creation_state) = &mut self.creation_state {
+                                    let forward = key.code == KeyCode::Right;
+                                    match creation_state.current_field {
+                                        CreationField::RuleProxy => creation_state.cycle_rule_proxy(forward),
+                                        CreationField::RuleAction => creation_state.cycle_rule_action(forward),
+                                        _ => {}
+                                    }
+                                }
+                            }
                             KeyCode::Enter => {
                                 if let Some(creation_state) = &mut self.creation_state {
                                     match creation_state.current_field {
-                                        CreationField::RedirectPorts => {
-                                            if !creation_state.current_port_input.is_empty() {
-                                                creation_state.redirect_ports.push(
-                                                    creation_state.current_port_input.clone()
-                                                );
-                                                creation_state.current_port_input.clear();
+                                        CreationField::ProxyUri => creation_state.apply_proxy_uri(),
+                                        CreationField::AddProxy => {
+                                            if !creation_state.has_proxy_draft() {
+                                                creation_state.next_field();
+                                            } else if creation_state.push_proxy() {
+                                                creation_state.current_field = CreationField::ProxyType;
                                             }
                                         }
+                                        CreationField::RedirectPorts => creation_state.push_rule(),
                                         CreationField::Confirm => {
-                                            self.create_configuration().await;
-                                            self.input_mode = InputMode::Normal;
-                                            self.creation_state = None;
+                                            if creation_state.is_valid(&self.configurations)
+                                                && self.create_configuration().await
+                                            {
+                                                self.input_mode = InputMode::Normal;
+                                                self.creation_state = None;
+                                            }
                                         }
                                         _ => creation_state.next_field(),
                                     }
@@ -274,39 +848,28 @@ impl App {
                             }
                             KeyCode::Char(c) => {
                                 if let Some(creation_state) = &mut self.creation_state {
-                                    match creation_state.current_field {
-                                        CreationField::Name => creation_state.name.push(c),
-                                        CreationField::ProxyType => creation_state.proxy_type.push(c),
-                                        CreationField::ProxyUrl => creation_state.proxy_url.push(c),
-                                        CreationField::ProxyPort => {
-                                            if c.is_ascii_digit() {
-                                                creation_state.proxy_port.push(c);
-                                            }
-                                        }
-                                        CreationField::ProxyLogin => { creation_state.proxy_login.push(c) }
-                                        CreationField::ProxyPassword => { creation_state.proxy_password.push(c) }
-                                        CreationField::RedirectPorts => {
-                                            if (c.is_ascii_digit()) || (c == ':') {
-                                                creation_state.current_port_input.push(c);
-                                            }
-                                        }
-                                        CreationField::Confirm => {}
-                                    }
+                                    creation_state.push_char(c);
                                 }
                             }
                             KeyCode::Backspace => {
                                 if let Some(creation_state) = &mut self.creation_state {
                                     match creation_state.current_field {
                                         CreationField::Name => { creation_state.name.pop(); }
+                                        CreationField::ProxyUri => { creation_state.proxy_uri.pop(); }
                                         CreationField::ProxyType => { creation_state.proxy_type.pop(); }
                                         CreationField::ProxyUrl => { creation_state.proxy_url.pop(); }
                                         CreationField::ProxyPort => { creation_state.proxy_port.pop(); }
                                         CreationField::ProxyLogin => { creation_state.proxy_login.pop(); }
                                         CreationField::ProxyPassword => { creation_state.proxy_password.pop(); }
+                                        CreationField::AddProxy => creation_state.pop_proxy(),
                                         CreationField::RedirectPorts => {
-                                            creation_state.current_port_input.pop();
+                                            if creation_state.current_port_input.pop().is_none() {
+                                                creation_state.rules.pop();
+                                            }
                                         }
-                                        CreationField::Confirm => {}
+                                        CreationField::RuleProxy
+                                        | CreationField::RuleAction
+                                        | CreationField::Confirm => {}
                                     }
                                 }
                             }
@@ -318,38 +881,127 @@ impl App {
         }
     }
 
-    async fn create_configuration(&mut self) {
-        if let Some(creation_state) = &self.creation_state {
-            let proxy = Proxy {
-                proxy_type: creation_state.proxy_type.clone(),
-                url: creation_state.proxy_url.clone(),
-                port: creation_state.proxy_port.parse().unwrap_or(0),
-                login: creation_state.proxy_login.clone(),
-                password: creation_state.proxy_password.clone()
-            };
-
-            let rules = creation_state.redirect_ports.iter().map(|port| {
-                IptablesRule {
-                    dport: port.parse().unwrap_or(0.to_string()),
-                    to_port: 14888,
-                    action: "REDIRECT".to_string(),
-                }
-            }).collect();
+    async fn create_configuration(&mut self) -> bool {
+        if let Some(creation_state) = &mut self.creation_state {
+            // whatever is still typed into the proxy and port fields counts as added
+            if creation_state.has_proxy_draft() && !creation_state.push_proxy() {
+                return false;
+            }
+            creation_state.push_rule();
 
             let config = Configuration::new(
                 creation_state.name.clone(),
-                vec![proxy],
-                rules,
-            ).await;
+                std::mem::take(&mut creation_state.proxies),
+                std::mem::take(&mut creation_state.rules),
+            );
+
+            let saved = config
+                .validate(self.configurations.iter().map(|c| c.name.as_str()))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| config.save_new());
+            let record = Record::new(&audit::current_user(), Action::Create).configuration(&config).outcome(&saved);
+            if let Err(e) = record.append() {
+                self.status_message = Some(format!("│ Failed to write the history: {}", e));
+            }
+
+            if let Err(e) = saved {
+                creation_state.save_error = Some(e.to_string());
+                creation_state.proxies = config.proxies;
+                creation_state.rules = config.rules;
+                return false;
+            }
 
             self.configurations.push(config);
             self.filter_configurations();
+
+            return true;
         }
+
+        false
     }
 
     async fn deactivate_proxy(&mut self) {
-        bindings::deactivate_proxy().await;
-        self.set_active_config(None).await;
+        let Some(daemon) = &mut self.daemon else {
+            bindings::deactivate_proxy().await;
+            let active = self.active_config_index.map(|index| self.configurations[index].name.clone());
+            self.log(Record::new(&audit::current_user(), Action::Deactivate).name(active.as_deref()));
+            self.set_active_config(None).await;
+            return;
+        };
+
+        match daemon.deactivate().await {
+            Ok(()) => {
+                self.active_config_index = None;
+                self.proxy_health.clear();
+            }
+            Err(e) => self.status_message = Some(format!("│ Deactivation failed: {}", e)),
+        }
+    }
+
+    fn render_connections(&self, f: &mut Frame, statistics: &Result<Statistics, String>, area: Rect) {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Blue));
+
+        let statistics = match statistics {
+            Ok(statistics) => statistics,
+            Err(e) => {
+                let error = Paragraph::new(e.as_str())
+                    .wrap(Wrap { trim: false })
+                    .style(Style::default().fg(Color::Red))
+                    .block(block.title("Connections"));
+                f.render_widget(error, area);
+                return;
+            }
+        };
+
+        let active = self.active_config_index.map(|index| self.configurations[index].name.as_str());
+        let mut title = format!("Connections · {} open", statistics.connections.len());
+        if let Some(traffic) = statistics.configurations.iter().find(|traffic| Some(traffic.name.as_str()) == active) {
+            title.push_str(&format!(
+                " · {}: {} connections, {} failed, ↑ {} ↓ {}",
+                traffic.name,
+                traffic.traffic.connections,
+                traffic.traffic.errors,
+                format_bytes(traffic.traffic.bytes_up),
+                format_bytes(traffic.traffic.bytes_down)
+            ));
+        }
+        if !statistics.accounting {
+            title.push_str(" · byte counters are off");
+        }
+
+        let rows: Vec<Row> = statistics
+            .connections
+            .iter()
+            .map(|connection| {
+                Row::new(vec![
+                    Cell::from(connection.destination.clone()),
+                    Cell::from(connection.proxy.clone()),
+                    Cell::from(format_age(connection.age_secs)),
+                    Cell::from(format_bytes(connection.bytes_up)),
+                    Cell::from(format_bytes(connection.bytes_down)),
+                ])
+            })
+            .collect();
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Percentage(30),
+                Constraint::Percentage(30),
+                Constraint::Percentage(10),
+                Constraint::Percentage(15),
+                Constraint::Percentage(15),
+            ],
+        )
+        .header(
+            Row::new(vec!["Destination", "Proxy", "Age", "↑", "↓"])
+                .style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+        )
+        .block(block.title(title));
+
+        f.render_widget(table, area);
     }
 
     fn ui(&self, f: &mut Frame) {
@@ -380,23 +1032,59 @@ impl App {
             ])
             .split(chunks[1]);
 
+        let searching = !Query::parse(&self.search_query).is_empty();
         let configs: Vec<ListItem> = self
-            .filtered_configs
+            .list_entries
             .iter()
-            .map(|&index| {
-                let config = &self.configurations[index];
-                let prefix = if Some(index) == self.active_config_index {
-                    "● "
-                } else {
-                    "○ "
-                };
-                let style = if Some(index) == self.active_config_index {
-                    Style::default().fg(Color::Green)
-                } else {
-                    Style::default().fg(Color::White)
-                };
-                ListItem::new(format!("{}{}", prefix, config.name)).style(style)
+            .map(|entry| match entry {
+                ListEntry::Group { name, count } => {
+                    let arrow = if self.collapsed.contains(name) { "▸" } else { "▾" };
+                    ListItem::new(format!("{} {} ({})", arrow, name, count))
+                        .style(Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))
+                }
+                &ListEntry::Config(index) => {
+                    let config = &self.configurations[index];
+                    let prefix = if Some(index) == self.active_config_index {
+                        "● "
+                    } else {
+                        "○ "
+                    };
+                    let style = if Some(index) == self.active_config_index {
+                        Style::default().fg(Color::Green)
+                    } else {
+                        Style::default().fg(Color::White)
+                    };
+                    // favourites are pinned above the groups, the others are indented under theirs
+                    let indent = if config.group.is_some() && !config.favourite && !searching { "  " } else { "" };
+                    let star = if config.favourite { "★ " } else { "" };
+                    let found = self.search_matches.get(&index);
+                    let positions = |field| found.map(|found| found.positions(field)).unwrap_or_default();
+                    let dim = Style::default().fg(Color::DarkGray);
+
+                    let mut spans = vec![Span::raw(format!("{}{}{}", indent, prefix, star))];
+                    spans.extend(highlight(&config.name, positions(Field::Name), Style::default()));
+                    for (i, tag) in config.tags.iter().enumerate() {
+                        spans.push(Span::styled(" #", dim));
+                        spans.extend(highlight(tag, positions(Field::Tag(i)), dim));
+                    }
+                    // the list is flat while searching, so show what else matched
+                    if let Some(found) = found {
+                        for (field, matched) in found.highlights.iter() {
+                            let text = match *field {
+                                Field::Group => config.group.clone().unwrap_or_default(),
+                                Field::Proxy(i) => format!("{}:{}", config.proxies[i].url, config.proxies[i].port),
+                                Field::Name | Field::Tag(_) => continue,
+                            };
+                            spans.push(Span::styled(if *field == Field::Group { " in " } else { " → " }, dim));
+                            spans.extend(highlight(&text, matched, dim));
+                        }
+                    }
+                    ListItem::new(Line::from(spans)).style(style)
+                }
             })
+            .chain(self.load_errors.iter().map(|error| {
+                ListItem::new(format!("✗ {}", error.file_name())).style(Style::default().fg(Color::Red))
+            }))
             .collect();
 
         let configs_list = List::new(configs)
@@ -412,18 +1100,41 @@ impl App {
 
         f.render_stateful_widget(configs_list, main_chunks[0], &mut self.config_list_state.clone());
 
-        if let Some(selected) = self.config_list_state.selected() {
-            if let Some(&real_index) = self.filtered_configs.get(selected) {
+        if let Some(statistics) = &self.statistics {
+            let details_area = Rect::union(main_chunks[1], main_chunks[2]);
+            self.render_connections(f, statistics, details_area);
+        } else if let Some(history) = &self.history {
+            render_history(f, history, Rect::union(main_chunks[1], main_chunks[2]));
+        } else if let Some(selected) = self.config_list_state.selected() {
+            if let Some(&ListEntry::Config(real_index)) = self.list_entries.get(selected) {
                 let config = &self.configurations[real_index];
                 
+                // proxswapd's health checks only cover the active configuration
+                let health = if Some(real_index) == self.active_config_index
+                    && self.proxy_health.len() == config.proxies.len()
+                {
+                    self.proxy_health.as_slice()
+                } else {
+                    &[]
+                };
+
                 let proxies: Vec<ListItem> = config
                     .proxies
                     .iter()
-                    .map(|proxy| {
+                    .enumerate()
+                    .map(|(index, proxy)| {
+                        let (suffix, color) = match health.get(index) {
+                            Some(status) if status.is_healthy() => (
+                                format!(" ✓ {}ms", status.latency_ms.unwrap_or_default()),
+                                Color::Green,
+                            ),
+                            Some(_) => (" ✗ unreachable".to_string(), Color::Red),
+                            None => (String::new(), Color::White),
+                        };
                         ListItem::new(format!(
-                            "{} - {}:{}",
-                            proxy.proxy_type, proxy.url, proxy.port
-                        )).style(Style::default().fg(Color::White))
+                            "{} - {}:{}{}",
+                            proxy.proxy_type, proxy.url, proxy.port, suffix
+                        )).style(Style::default().fg(color))
                     })
                     .collect();
 
@@ -439,9 +1150,12 @@ impl App {
                     .rules
                     .iter()
                     .map(|rule| {
+                        let destination = rule.destination
+                            .map(|destination| format!("{} ", destination))
+                            .unwrap_or_default();
                         ListItem::new(format!(
-                            "{} → {}",
-                            rule.dport, rule.to_port
+                            "{}{} → {} ({})",
+                            destination, rule.dport, rule.to_port, rule.action
                         )).style(Style::default().fg(Color::White))
                     })
                     .collect();
@@ -452,20 +1166,84 @@ impl App {
                         .borders(Borders::ALL)
                         .border_style(Style::default().fg(Color::Blue)));
 
-                f.render_widget(rules_list, main_chunks[2]);
+                if config.schedule.is_empty() {
+                    f.render_widget(rules_list, main_chunks[2]);
+                } else {
+                    // axis, one row per day and the windows as written in the file
+                    let rules_chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Min(3), Constraint::Length(11)])
+                        .split(main_chunks[2]);
+                    f.render_widget(rules_list, rules_chunks[0]);
+
+                    let now = schedule::minute_of_week();
+                    let title = if config.schedule.contains(now) {
+                        "Schedule · in a window".to_string()
+                    } else if let Some(next) = config.schedule.next_start(now) {
+                        format!("Schedule · next {}", schedule::format_minute_of_week(now + next))
+                    } else {
+                        "Schedule".to_string()
+                    };
+
+                    let timeline = Paragraph::new(schedule_timeline(&config.schedule, now, rules_chunks[1].width.saturating_sub(6)))
+                        .wrap(Wrap { trim: false })
+                        .block(Block::default()
+                            .title(title)
+                            .borders(Borders::ALL)
+                            .border_style(Style::default().fg(Color::Blue)));
+
+                    f.render_widget(timeline, rules_chunks[1]);
+                }
+            } else if let Some(error) = self.selected_load_error() {
+                let position = match (error.line, error.column) {
+                    (Some(line), Some(column)) => format!("line {}, column {}", line, column),
+                    _ => "unknown position".to_string(),
+                };
+
+                let details = Paragraph::new(vec![
+                    Line::from(Span::styled(
+                        error.path.to_string_lossy().to_string(),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    )),
+                    Line::from(position),
+                    Line::from(""),
+                    Line::from(error.message.clone()),
+                    Line::from(""),
+                    Line::from(vec![
+                        Span::styled("o", Style::default().fg(Color::Yellow)),
+                        Span::raw(": open in $EDITOR"),
+                    ]),
+                ])
+                .wrap(Wrap { trim: false })
+                .style(Style::default().fg(Color::Red))
+                .block(Block::default()
+                    .title("Failed to load")
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Red)));
+
+                let details_area = Rect::union(main_chunks[1], main_chunks[2]);
+                f.render_widget(details, details_area);
             }
         }
 
         let status = match self.input_mode {
+            InputMode::Normal if self.selected_load_error().is_some() => {
+                "Mode: Normal │ q: quit │ o: open in $EDITOR │ /: search │ ↑↓: navigate"
+            }
             InputMode::Normal => {
                 if self.active_config_index.is_some() {
-                    "Mode: Normal │ q: quit │ c: create │ x: deactivate proxy │ /: search │ ↑↓: navigate"
+                    "Mode: Normal │ q: quit │ c: create │ D: duplicate │ d: delete │ f: favourite │ T: trash │ E: export │ x: deactivate proxy │ t: connections │ h: history │ /: search │ ↑↓: navigate"
                 } else {
-                    "Mode: Normal │ q: quit │ c: create │ /: search │ ↑↓: navigate"
+                    "Mode: Normal │ q: quit │ c: create │ D: duplicate │ d: delete │ f: favourite │ T: trash │ E: export │ t: connections │ h: history │ /: search │ ↑↓: navigate"
                 }
             }
             InputMode::Editing => "Mode: Editing │ ESC: cancel │ Enter: confirm",
             InputMode::Creating => "Mode: Creating │ ESC: cancel │ ↑/↓: navigate │ Enter: confirm",
+            InputMode::Exporting => "Mode: Exporting │ ESC: cancel │ ↑/↓: navigate │ Enter: export",
+            InputMode::ConfirmExit => "Mode: Quitting │ y: deactivate and quit │ n: keep it and quit │ ESC: cancel",
+            InputMode::ConfirmDelete => "Mode: Deleting │ y: move it to the trash │ n/ESC: cancel",
+            InputMode::Trash => "Mode: Trash │ ESC: close │ ↑/↓: navigate │ Enter: restore",
+            InputMode::Duplicating => "Mode: Duplicating │ ESC: cancel │ Enter: save the copy",
         };
 
         let search_status = if !self.search_query.is_empty() {
@@ -480,15 +1258,150 @@ impl App {
             Style::default().fg(Color::White)
         };
 
-        let status_bar = Paragraph::new(format!("{} {}", status, search_status))
+        let message = self.status_message.as_deref().unwrap_or_default();
+
+        let status_bar = Paragraph::new(format!("{} {} {}", status, search_status, message))
             .block(Block::default()
                 .borders(Borders::ALL)
                 .border_style(Style::default().fg(Color::Blue)))
             .style(status_style);
         f.render_widget(status_bar, chunks[2]);
 
+        if matches!(self.input_mode, InputMode::ConfirmExit) {
+            let confirm_area = centered_rect(50, 20, f.area());
+            f.render_widget(Clear, confirm_area);
+
+            let name = self
+                .active_config_index
+                .map(|index| self.configurations[index].name.as_str())
+                .unwrap_or_default();
+            let confirm = Paragraph::new(vec![
+                Line::from(format!("\"{}\" is still active.", name)),
+                Line::from(""),
+                Line::from(vec![
+                    Span::styled("y", Style::default().fg(Color::Yellow)),
+                    Span::raw(": deactivate and quit   "),
+                    Span::styled("n", Style::default().fg(Color::Yellow)),
+                    Span::raw(": keep it and quit"),
+                ]),
+            ])
+            .alignment(Alignment::Center)
+            .block(Block::default()
+                .title("Quit")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Yellow)));
+
+            f.render_widget(confirm, confirm_area);
+        }
+
+        if matches!(self.input_mode, InputMode::ConfirmDelete) {
+            if let Some(index) = self.selected_config_index() {
+                let confirm_area = centered_rect(50, 20, f.area());
+                f.render_widget(Clear, confirm_area);
+
+                let mut lines = vec![Line::from(format!(
+                    "Move \"{}\" to the trash?",
+                    self.configurations[index].name
+                ))];
+                if Some(index) == self.active_config_index {
+                    lines.push(Line::from("It is active and will be deactivated first."));
+                }
+                lines.push(Line::from(""));
+                lines.push(Line::from(vec![
+                    Span::styled("y", Style::default().fg(Color::Yellow)),
+                    Span::raw(": delete   "),
+                    Span::styled("n", Style::default().fg(Color::Yellow)),
+                    Span::raw(": cancel"),
+                ]));
+
+                let confirm = Paragraph::new(lines)
+                    .alignment(Alignment::Center)
+                    .block(Block::default()
+                        .title("Delete")
+                        .borders(Borders::ALL)
+                        .border_style(Style::default().fg(Color::Yellow)));
+
+                f.render_widget(confirm, confirm_area);
+            }
+        }
+
+        if matches!(self.input_mode, InputMode::Duplicating) {
+            if let Some(index) = self.selected_config_index() {
+                let duplicate_area = centered_rect(50, 20, f.area());
+                f.render_widget(Clear, duplicate_area);
+
+                let mut lines = vec![Line::from(vec![
+                    Span::styled("Name: ", Style::default().fg(Color::Yellow)),
+                    Span::raw(format!("{}█", self.duplicate_name)),
+                ])];
+                if let Some(error) = &self.duplicate_error {
+                    lines.push(Line::from(Span::styled(error.clone(), Style::default().fg(Color::Red))));
+                }
+
+                let duplicate = Paragraph::new(lines)
+                    .wrap(Wrap { trim: false })
+                    .block(Block::default()
+                        .title(format!("Duplicate {}", self.configurations[index].name))
+                        .borders(Borders::ALL)
+                        .border_style(Style::default().fg(Color::Yellow)));
+
+                f.render_widget(duplicate, duplicate_area);
+            }
+        }
+
+        if matches!(self.input_mode, InputMode::Trash) {
+            let trash_area = centered_rect(50, 40, f.area());
+            f.render_widget(Clear, trash_area);
+
+            let now = SystemTime::now();
+            let entries: Vec<ListItem> = self
+                .trash
+                .iter()
+                .map(|entry| {
+                    let age = now.duration_since(entry.deleted).unwrap_or_default().as_secs();
+                    ListItem::new(format!("{} · deleted {} ago", entry.name, format_age(age)))
+                })
+                .collect();
+
+            let trash_list = List::new(entries)
+                .block(Block::default()
+                    .title("Trash")
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Yellow)))
+                .highlight_style(
+                    Style::default()
+                        .bg(Color::DarkGray)
+                        .add_modifier(Modifier::BOLD),
+                );
+
+            f.render_stateful_widget(trash_list, trash_area, &mut self.trash_list_state.clone());
+        }
+
+        if matches!(self.input_mode, InputMode::Exporting) {
+            let export_area = centered_rect(40, 30, f.area());
+            f.render_widget(Clear, export_area);
+
+            let formats: Vec<ListItem> = ExportFormat::ALL
+                .iter()
+                .map(|format| ListItem::new(format.label()))
+                .collect();
+
+            let formats_list = List::new(formats)
+                .block(Block::default()
+                    .title("Export As")
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(Color::Yellow)))
+                .highlight_style(
+                    Style::default()
+                        .bg(Color::DarkGray)
+                        .add_modifier(Modifier::BOLD),
+                );
+
+            f.render_stateful_widget(formats_list, export_area, &mut self.export_list_state.clone());
+        }
+
         if let Some(creation_state) = &self.creation_state {
-            let creation_area = centered_rect(60, 30, f.area());
+            let creation_area = centered_rect(70, 70, f.area());
             f.render_widget(Clear, creation_area);
             
             let creation_block = Block::default()
@@ -520,32 +1433,77 @@ impl App {
                 Line::from(vec![label_span, value_span])
             };
 
-            content.push(style_field("Name", &creation_state.name, 
-                matches!(creation_state.current_field, CreationField::Name)));
-            content.push(style_field("Proxy Type", &creation_state.proxy_type,
-                matches!(creation_state.current_field, CreationField::ProxyType)));
-            content.push(style_field("Proxy URL", &creation_state.proxy_url,
-                matches!(creation_state.current_field, CreationField::ProxyUrl)));
-            content.push(style_field("Proxy Port", &creation_state.proxy_port,
-                matches!(creation_state.current_field, CreationField::ProxyPort)));
-            content.push(style_field("Proxy Login", &creation_state.proxy_login,
-                    matches!(creation_state.current_field, CreationField::ProxyLogin)));
-            content.push(style_field("Proxy Password", &creation_state.proxy_password,
-                matches!(creation_state.current_field, CreationField::ProxyPassword)));
-            
-            let ports_str = if creation_state.redirect_ports.is_empty() {
+            let form_field = |label: &str, value: &str, field: CreationField| -> Line<'static> {
+                let mut line = style_field(label, value, creation_state.current_field == field);
+                if let Some(error) = creation_state.field_error(field, &self.configurations) {
+                    line.push_span(Span::styled(
+                        format!("  ✗ {}", error),
+                        Style::default().fg(Color::Red),
+                    ));
+                }
+                line
+            };
+
+            content.push(form_field("Name", &creation_state.name, CreationField::Name));
+            let proxies_str = if creation_state.proxies.is_empty() {
                 "None".to_string()
             } else {
-                creation_state.redirect_ports.join(", ")
+                creation_state.proxies.iter().zip(BASE_LOCAL_PORT..).enumerate()
+                    .map(|(i, (proxy, local_port))| {
+                        format!("#{} {} {}:{} (:{})", i, proxy.proxy_type, proxy.url, proxy.port, local_port)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ")
             };
-            
-            content.push(style_field("Redirect Ports/Ranges", &ports_str,
-                matches!(creation_state.current_field, CreationField::RedirectPorts)));
+
+            content.push(style_field("Proxies", &proxies_str, false));
+            content.push(form_field("Paste Proxy URI", &creation_state.proxy_uri, CreationField::ProxyUri));
+            content.push(form_field("Proxy Type", &creation_state.proxy_type, CreationField::ProxyType));
+            content.push(form_field("Proxy URL", &creation_state.proxy_url, CreationField::ProxyUrl));
+            content.push(form_field("Proxy Port", &creation_state.proxy_port, CreationField::ProxyPort));
+            content.push(form_field("Proxy Login", &creation_state.proxy_login, CreationField::ProxyLogin));
+            content.push(form_field("Proxy Password", &creation_state.proxy_password, CreationField::ProxyPassword));
+            content.push(form_field("Add Proxy", "[Enter]", CreationField::AddProxy));
+
+            content.push(Line::from(String::from("")));
+
+            let rule_target = format!("◀ #{} (:{}) ▶",
+                creation_state.rule_proxy, BASE_LOCAL_PORT + creation_state.rule_proxy as u16);
+            content.push(form_field("Rule Proxy", &rule_target, CreationField::RuleProxy));
+            let rule_action = format!("◀ {} ▶", creation_state.rule_action);
+            content.push(form_field("Rule Action", &rule_action, CreationField::RuleAction));
+
+            let rules_str = if creation_state.rules.is_empty() {
+                "None".to_string()
+            } else {
+                creation_state.rules.iter()
+                    .map(|rule| format!("{} → {} ({})", rule.dport, rule.to_port, rule.action))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+
+            content.push(form_field("Redirect Ports/Ranges", &rules_str, CreationField::RedirectPorts));
             
             if matches!(creation_state.current_field, CreationField::RedirectPorts) {
                 content.push(style_field("Current Input", &creation_state.current_port_input, true));
             }
 
+            content.push(form_field("Create", "[Enter]", CreationField::Confirm));
+            if matches!(creation_state.current_field, CreationField::Confirm)
+                && !creation_state.is_valid(&self.configurations)
+            {
+                content.push(Line::from(Span::styled(
+                    "Fix the errors above before creating the configuration",
+                    Style::default().fg(Color::Red),
+                )));
+            }
+            if let Some(error) = &creation_state.save_error {
+                content.push(Line::from(Span::styled(
+                    format!("Failed to save: {}", error),
+                    Style::default().fg(Color::Red),
+                )));
+            }
+
             content.push(Line::from(String::from("")));
             content.push(Line::from("─".repeat(40)));
             
@@ -554,9 +1512,17 @@ impl App {
                     Span::styled("↑/↓".to_string(), Style::default().fg(Color::Yellow)),
                     Span::raw(": Navigate Fields"),
                 ]),
+                Line::from(vec![
+                    Span::styled("←/→".to_string(), Style::default().fg(Color::Yellow)),
+                    Span::raw(": Pick Rule Proxy/Action"),
+                ]),
                 Line::from(vec![
                     Span::styled("Enter".to_string(), Style::default().fg(Color::Yellow)),
-                    Span::raw(": Confirm Field/Add Port"),
+                    Span::raw(": Confirm Field/Add Proxy/Add Port"),
+                ]),
+                Line::from(vec![
+                    Span::styled("Backspace".to_string(), Style::default().fg(Color::Yellow)),
+                    Span::raw(": Remove Last Proxy/Port"),
                 ]),
                 Line::from(vec![
                     Span::styled("Esc".to_string(), Style::default().fg(Color::Yellow)),
@@ -574,10 +1540,47 @@ impl App {
         }
     }
 
+    fn selected_config_index(&self) -> Option<usize> {
+        match self.list_entries.get(self.config_list_state.selected()?)? {
+            &ListEntry::Config(index) => Some(index),
+            ListEntry::Group { .. } => None,
+        }
+    }
+
+    fn export_selected(&mut self, format: ExportFormat) {
+        let Some(index) = self.selected_config_index() else {
+            return;
+        };
+        let config = &self.configurations[index];
+        let path = format!("{}/{}", *EXPORT_DIR, format.file_name(&config.name));
+
+        let written = std::fs::create_dir_all(&*EXPORT_DIR)
+            .and_then(|_| export::write(Path::new(&path), &export::export(config, format)));
+
+        self.status_message = Some(match written {
+            Ok(()) => format!("│ Exported to {}", path),
+            Err(e) => format!("│ Export failed: {}", e),
+        });
+    }
+
+    fn list_len(&self) -> usize {
+        self.list_entries.len() + self.load_errors.len()
+    }
+
+    // broken files are listed after the configurations that did load
+    fn selected_load_error(&self) -> Option<&LoadError> {
+        let selected = self.config_list_state.selected()?;
+        self.load_errors.get(selected.checked_sub(self.list_entries.len())?)
+    }
+
     fn next(&mut self) {
+        if self.list_len() == 0 {
+            return;
+        }
+
         let i = match self.config_list_state.selected() {
             Some(i) => {
-                if i >= self.filtered_configs.len() - 1 {
+                if i >= self.list_len() - 1 {
                     0
                 } else {
                     i + 1
@@ -589,10 +1592,14 @@ impl App {
     }
 
     fn previous(&mut self) {
+        if self.list_len() == 0 {
+            return;
+        }
+
         let i = match self.config_list_state.selected() {
             Some(i) => {
                 if i == 0 {
-                    self.filtered_configs.len() - 1
+                    self.list_len() - 1
                 } else {
                     i - 1
                 }
@@ -602,16 +1609,137 @@ impl App {
         self.config_list_state.select(Some(i));
     }
 
-    fn delete_selected(&mut self) {
+    async fn open_broken_in_editor<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let Some(error) = self.selected_load_error() else {
+            return Ok(());
+        };
+        let path = error.path.clone();
+        let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+        let mut editor = editor.split_whitespace();
+        let program = editor.next().unwrap_or("vi").to_string();
+        let args: Vec<String> = editor.map(str::to_string).collect();
+
+        disable_raw_mode()?;
+        execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, DisableBracketedPaste)?;
+
+        let _ = Command::new(&program).args(&args).arg(&path).status();
+
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
+        terminal.clear()?;
+
+        let position = self.load_errors.iter().position(|e| e.path == path);
+        if let Some(position) = position {
+            match loader::load_configuration(&path).await {
+                Ok(configuration) => {
+                    self.load_errors.remove(position);
+                    self.configurations.push(configuration);
+                    self.filter_configurations();
+                }
+                Err(error) => self.load_errors[position] = error,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_selected(&mut self) {
         if let Some(selected) = self.config_list_state.selected() {
-            if let Some(&real_index) = self.filtered_configs.get(selected) {
-                self.configurations[real_index].delete_configuration().unwrap();
-                self.configurations.remove(real_index);
+            if let Some(&ListEntry::Config(real_index)) = self.list_entries.get(selected) {
+                // redsocks and the rules would otherwise stay with nothing left to deactivate
+                if Some(real_index) == self.active_config_index {
+                    self.deactivate_proxy().await;
+                    if self.active_config_index.is_some() {
+                        return;
+                    }
+                }
+
+                let deleted = self.configurations[real_index].delete_configuration();
+                let record = Record::new(&audit::current_user(), Action::Delete)
+                    .configuration(&self.configurations[real_index])
+                    .outcome(&deleted);
+                self.log(record);
+                if let Err(e) = deleted {
+                    self.status_message = Some(format!("│ Failed to delete the configuration: {}", e));
+                    return;
+                }
+                let name = self.configurations.remove(real_index).name;
+                // indexes past the deleted configuration shifted, look the active one up again
+                self.active_config_index = state::find_active_config(&self.configurations);
                 self.filter_configurations();
-                if selected >= self.filtered_configs.len() {
-                    self.config_list_state.select(Some(self.filtered_configs.len() - 1));
+                if selected >= self.list_len() {
+                    self.config_list_state.select(self.list_len().checked_sub(1));
                 }
+                self.status_message = Some(format!("│ Moved {} to the trash, u: undo", name));
+            }
+        }
+    }
+
+    // returns whether the copy was saved, the dialog stays open with the error otherwise
+    fn duplicate_selected(&mut self) -> bool {
+        let Some(index) = self.selected_config_index() else {
+            return true;
+        };
+
+        let copy = self.configurations[index].duplicate(self.duplicate_name.trim());
+        let saved = copy
+            .validate(self.configurations.iter().map(|config| config.name.as_str()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| copy.save_new());
+        self.log(Record::new(&audit::current_user(), Action::Create).configuration(&copy).outcome(&saved));
+
+        if let Err(e) = saved {
+            self.duplicate_error = Some(e.to_string());
+            return false;
+        }
+
+        let name = copy.name.clone();
+        self.configurations.push(copy);
+        self.filter_configurations();
+        self.select_config(self.configurations.len() - 1);
+        self.status_message = Some(format!("│ Saved {}", name));
+
+        true
+    }
+
+    async fn undo_delete(&mut self) {
+        match trash::list() {
+            Ok(entries) => match entries.first() {
+                Some(entry) => self.restore_from_trash(entry).await,
+                None => self.status_message = Some("│ Nothing to undo, the trash is empty".to_string()),
+            },
+            Err(e) => self.status_message = Some(format!("│ Failed to read the trash: {}", e)),
+        }
+    }
+
+    fn open_trash(&mut self) {
+        match trash::list() {
+            Ok(entries) if entries.is_empty() => self.status_message = Some("│ The trash is empty".to_string()),
+            Ok(entries) => {
+                self.trash = entries;
+                self.trash_list_state.select(Some(0));
+                self.input_mode = InputMode::Trash;
             }
+            Err(e) => self.status_message = Some(format!("│ Failed to read the trash: {}", e)),
+        }
+    }
+
+    async fn restore_from_trash(&mut self, entry: &trash::Entry) {
+        let restored = trash::restore(entry).await;
+        let record = match &restored {
+            Ok(config) => Record::new(&audit::current_user(), Action::Restore).configuration(config),
+            Err(_) => Record::new(&audit::current_user(), Action::Restore).name(Some(&entry.name)),
+        };
+        self.log(record.outcome(&restored));
+
+        match restored {
+            Ok(config) => {
+                self.configurations.push(config);
+                self.filter_configurations();
+                self.select_config(self.configurations.len() - 1);
+                self.status_message = Some(format!("│ Restored {}", entry.name));
+            }
+            Err(e) => self.status_message = Some(format!("│ Failed to restore {}: {}", entry.name, e)),
         }
     }
 
@@ -623,16 +1751,266 @@ impl App {
         };
     }
 
+    // favourites first, then the configurations without a group, then the groups by name. A search
+    // lists the matches by how well they match instead.
     fn filter_configurations(&mut self) {
-        self.filtered_configs = (0..self.configurations.len())
-            .filter(|&i| {
-                self.search_query.is_empty()
-                    || self.configurations[i]
-                        .name
-                        .to_lowercase()
-                        .contains(&self.search_query.to_lowercase())
-            })
+        let query = Query::parse(&self.search_query);
+        if !query.is_empty() {
+            self.search_matches = (0..self.configurations.len())
+                .filter_map(|i| Some((i, query.matches(&self.configurations[i])?)))
+                .collect();
+            let mut ranked: Vec<usize> = self.search_matches.keys().copied().collect();
+            ranked.sort_by_key(|&i| {
+                let name = &self.configurations[i].name;
+                (std::cmp::Reverse(self.search_matches[&i].score), name.len(), name.clone())
+            });
+            self.list_entries = ranked.into_iter().map(ListEntry::Config).collect();
+            return;
+        }
+        self.search_matches.clear();
+
+        let configs = &self.configurations;
+        let mut entries: Vec<ListEntry> = (0..configs.len())
+            .filter(|&i| configs[i].favourite)
+            .chain((0..configs.len()).filter(|&i| !configs[i].favourite && configs[i].group.is_none()))
+            .map(ListEntry::Config)
             .collect();
+
+        let mut groups: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, config) in configs.iter().enumerate() {
+            if let Some(group) = config.group.as_deref().filter(|_| !config.favourite) {
+                groups.entry(group).or_default().push(i);
+            }
+        }
+        for (name, members) in groups {
+            entries.push(ListEntry::Group {
+                name: name.to_string(),
+                count: members.len(),
+            });
+            if self.collapsed.contains(name) {
+                continue;
+            }
+            entries.extend(members.into_iter().map(ListEntry::Config));
+        }
+
+        self.list_entries = entries;
+    }
+
+    fn select_config(&mut self, index: usize) {
+        if let Some(group) = self.configurations[index].group.clone() {
+            if self.collapsed.remove(&group) {
+                self.filter_configurations();
+            }
+        }
+        if let Some(position) = self.list_entries.iter().position(|entry| *entry == ListEntry::Config(index)) {
+            self.config_list_state.select(Some(position));
+        }
+    }
+
+    fn toggle_group(&mut self, name: &str) {
+        if !self.collapsed.remove(name) {
+            self.collapsed.insert(name.to_string());
+        }
+        self.filter_configurations();
+    }
+
+    fn toggle_favourite(&mut self) {
+        let Some(index) = self.selected_config_index() else {
+            return;
+        };
+
+        let config = &mut self.configurations[index];
+        config.favourite = !config.favourite;
+        if let Err(e) = config.save() {
+            config.favourite = !config.favourite;
+            self.status_message = Some(format!("│ Failed to save {}: {}", config.name, e));
+            return;
+        }
+        self.filter_configurations();
+        self.select_config(index);
+    }
+}
+
+fn render_history(f: &mut Frame, history: &Result<Vec<Record>, String>, area: Rect) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::Blue));
+
+    let records = match history {
+        Ok(records) => records,
+        Err(e) => {
+            let error = Paragraph::new(e.as_str())
+                .wrap(Wrap { trim: false })
+                .style(Style::default().fg(Color::Red))
+                .block(block.title("History"));
+            f.render_widget(error, area);
+            return;
+        }
+    };
+
+    let rows: Vec<Row> = records
+        .iter()
+        .map(|record| {
+            let result = match &record.error {
+                Some(error) => format!("{}: {}", record.result, error),
+                None => record.result.to_string(),
+            };
+            let color = if record.error.is_some() { Color::Red } else { Color::White };
+            Row::new(vec![
+                Cell::from(audit::format_timestamp(&record.timestamp)),
+                Cell::from(record.user.clone()),
+                Cell::from(record.action.to_string()),
+                Cell::from(record.configuration.clone().unwrap_or_default()),
+                Cell::from(result),
+            ])
+            .style(Style::default().fg(color))
+        })
+        .collect();
+
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(19),
+            Constraint::Percentage(15),
+            Constraint::Length(10),
+            Constraint::Percentage(20),
+            Constraint::Min(10),
+        ],
+    )
+    .header(
+        Row::new(vec!["Time", "User", "Action", "Configuration", "Result"])
+            .style(Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+    )
+    .block(block.title(format!("History · {} records, newest first", records.len())));
+
+    f.render_widget(table, area);
+}
+
+// `text` with the characters at `positions` in yellow
+fn highlight(text: &str, positions: &[usize], style: Style) -> Vec<Span<'static>> {
+    let highlighted = style.fg(Color::Yellow).add_modifier(Modifier::BOLD);
+    let mut spans: Vec<Span> = Vec::new();
+    let mut run = String::new();
+    let mut run_highlighted = false;
+
+    for (i, c) in text.chars().enumerate() {
+        let matched = positions.contains(&i);
+        if matched != run_highlighted && !run.is_empty() {
+            spans.push(Span::styled(std::mem::take(&mut run), if run_highlighted { highlighted } else { style }));
+        }
+        run_highlighted = matched;
+        run.push(c);
+    }
+    if !run.is_empty() {
+        spans.push(Span::styled(run, if run_highlighted { highlighted } else { style }));
+    }
+
+    spans
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        _ => format!("{}h", seconds / 3600),
+    }
+}
+
+// a week as rows of `cells` characters each, the current time highlighted
+fn schedule_timeline(schedule: &Schedule, now: u32, cells: u16) -> Vec<Line<'static>> {
+    let cells = cells.max(1) as u32;
+    let minutes_per_cell = (24 * 60 / cells).max(1);
+
+    let mut axis = vec![' '; cells as usize];
+    for hour in [0, 6, 12, 18] {
+        let position = (hour * cells / 24) as usize;
+        for (offset, digit) in hour.to_string().chars().enumerate() {
+            if let Some(cell) = axis.get_mut(position + offset) {
+                *cell = digit;
+            }
+        }
+    }
+
+    let mut lines = vec![Line::from(format!("    {}", axis.into_iter().collect::<String>()))];
+
+    for (day, name) in schedule::DAYS.iter().enumerate() {
+        let mut spans = vec![Span::raw(format!("{:<4}", name))];
+
+        for cell in 0..cells {
+            let start = day as u32 * 24 * 60 + cell * 24 * 60 / cells;
+            let covered = schedule.contains(start + minutes_per_cell / 2);
+            let style = if (start..start + minutes_per_cell).contains(&now) {
+                Style::default().fg(Color::Yellow)
+            } else if covered {
+                Style::default().fg(Color::Green)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            spans.push(Span::styled(if covered { "█" } else { "·" }, style));
+        }
+
+        lines.push(Line::from(spans));
+    }
+
+    let windows: Vec<String> = schedule.windows().iter().map(Window::to_string).collect();
+    lines.push(Line::from(windows.join(" · ")));
+
+    lines
+}
+
+// best effort, it runs from the panic hook too
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste,
+        Show
+    );
+}
+
+/// Applies `policy` when the TUI ended without the user picking, after a signal or a panic.
+/// `ask` falls back to keeping the configuration when there's no terminal to ask on.
+pub async fn apply_exit_policy(policy: ExitPolicy, can_ask: bool) {
+    if state::read_active_config().is_none() || Client::connect().await.is_ok() {
+        return;
+    }
+
+    let deactivate = match policy {
+        ExitPolicy::Keep => false,
+        ExitPolicy::Deactivate => true,
+        ExitPolicy::Ask if can_ask && io::stdin().is_terminal() => {
+            print!("A proxy configuration is still active, deactivate it? [y/N] ");
+            let _ = io::stdout().flush();
+
+            let mut answer = String::new();
+            let _ = io::stdin().read_line(&mut answer);
+            answer.trim().eq_ignore_ascii_case("y")
+        }
+        ExitPolicy::Ask => false,
+    };
+
+    if deactivate {
+        bindings::deactivate_proxy().await;
+        let record = Record::new(&audit::current_user(), Action::Deactivate).name(state::read_active_config().as_deref());
+        let _ = record.append();
+        let _ = state::write_active_config(None);
     }
 }
 
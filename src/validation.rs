@@ -0,0 +1,318 @@
+use crate::configuration::{
+    Configuration, IptablesRule, Proxy, ProxyKind, RuleAction, BASE_LOCAL_PORT,
+};
+use crate::uri::UriError;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    EmptyName,
+    InvalidName(String),
+    DuplicateName(String),
+    UnsupportedProxyType(String),
+    EmptyProxyUrl,
+    InvalidProxyUrl(String),
+    InvalidProxyUri(UriError),
+    InvalidPort(String),
+    IncompleteCredentials,
+    InvalidCredentials,
+    NoProxies,
+    InvalidDport(String),
+    TooManyPorts(String),
+    InvalidDestination(String),
+    UnknownRuleTarget(u16),
+    UnsupportedAction(String),
+    InvalidSchedule(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::EmptyName => write!(f, "name can't be empty"),
+            ValidationError::InvalidName(name) => {
+                write!(f, "\"{}\" isn't a valid file name", name)
+            }
+            ValidationError::DuplicateName(name) => {
+                write!(f, "a configuration named \"{}\" already exists", name)
+            }
+            ValidationError::UnsupportedProxyType(proxy_type) => write!(
+                f,
+                "unsupported proxy type \"{}\", expected one of {}",
+                proxy_type,
+                ProxyKind::ALL.map(|kind| kind.as_str()).join(", ")
+            ),
+            ValidationError::EmptyProxyUrl => write!(f, "proxy host can't be empty"),
+            ValidationError::InvalidProxyUrl(url) => {
+                write!(f, "\"{}\" isn't a valid host", url)
+            }
+            ValidationError::InvalidProxyUri(e) => write!(f, "{}", e),
+            ValidationError::InvalidPort(port) => {
+                write!(f, "\"{}\" isn't a port between 1 and 65535", port)
+            }
+            ValidationError::IncompleteCredentials => {
+                write!(f, "login and password must be set together")
+            }
+            ValidationError::InvalidCredentials => {
+                write!(f, "login and password can't contain whitespace, ';', '{{' or '}}'")
+            }
+            ValidationError::NoProxies => write!(f, "add at least one proxy"),
+            ValidationError::InvalidDport(dport) => {
+                write!(f, "\"{}\" isn't a port, a start:end range or a comma separated list of them", dport)
+            }
+            ValidationError::TooManyPorts(dport) => write!(
+                f,
+                "\"{}\" lists more than {} ports, a range counts as 2; split it into several rules",
+                dport, MAX_MULTIPORT
+            ),
+            ValidationError::InvalidDestination(destination) => {
+                write!(f, "\"{}\" isn't an IPv4 address or CIDR block", destination)
+            }
+            ValidationError::UnknownRuleTarget(port) => {
+                write!(f, "no proxy listens on local port {}", port)
+            }
+            ValidationError::UnsupportedAction(action) => write!(
+                f,
+                "unsupported action \"{}\", expected one of {}",
+                action,
+                RuleAction::ALL.map(|action| action.as_str()).join(", ")
+            ),
+            ValidationError::InvalidSchedule(window) => {
+                write!(f, "\"{}\" isn't a window like mon-fri 09:00-18:00", window)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// what iptables' multiport match takes
+const MAX_MULTIPORT: usize = 15;
+
+pub fn validate_name<'a>(
+    name: &str,
+    mut taken: impl Iterator<Item = &'a str>,
+) -> Result<(), ValidationError> {
+    if name.is_empty() {
+        return Err(ValidationError::EmptyName);
+    }
+
+    // the name becomes a file name under CONFIG_DIR, it must not point anywhere else
+    if name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(ValidationError::InvalidName(name.to_string()));
+    }
+
+    if taken.any(|other| other == name) {
+        return Err(ValidationError::DuplicateName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_host(url: &str) -> Result<(), ValidationError> {
+    if url.is_empty() {
+        return Err(ValidationError::EmptyProxyUrl);
+    }
+
+    if !is_redsocks_value(url) {
+        return Err(ValidationError::InvalidProxyUrl(url.to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_credentials(login: &str, password: &str) -> Result<(), ValidationError> {
+    if login.is_empty() != password.is_empty() {
+        return Err(ValidationError::IncompleteCredentials);
+    }
+
+    if !is_redsocks_value(login) || !is_redsocks_value(password) {
+        return Err(ValidationError::InvalidCredentials);
+    }
+
+    Ok(())
+}
+
+// values are pasted verbatim into the redsocks config
+fn is_redsocks_value(value: &str) -> bool {
+    !value.contains(|c: char| c.is_whitespace() || c == ';' || c == '{' || c == '}')
+}
+
+pub fn parse_port(port: &str) -> Result<u16, ValidationError> {
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 => Ok(port),
+        _ => Err(ValidationError::InvalidPort(port.to_string())),
+    }
+}
+
+impl Proxy {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate_host(&self.url)?;
+
+        if self.port == 0 {
+            return Err(ValidationError::InvalidPort(self.port.to_string()));
+        }
+
+        validate_credentials(&self.login, &self.password)
+    }
+}
+
+impl IptablesRule {
+    pub fn validate(&self, proxy_count: usize) -> Result<(), ValidationError> {
+        let targets = BASE_LOCAL_PORT as usize..BASE_LOCAL_PORT as usize + proxy_count;
+        if self.action == RuleAction::Redirect && !targets.contains(&(self.to_port as usize)) {
+            return Err(ValidationError::UnknownRuleTarget(self.to_port));
+        }
+
+        // a single port or range doesn't need the multiport match
+        let ranges = self.dport.ranges();
+        let ports: usize = ranges.iter().map(|range| if range.start == range.end { 1 } else { 2 }).sum();
+        if ranges.len() > 1 && ports > MAX_MULTIPORT {
+            return Err(ValidationError::TooManyPorts(self.dport.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Configuration {
+    /// Checks the whole configuration, `taken` being the names of the other configurations.
+    pub fn validate<'a>(
+        &self,
+        taken: impl Iterator<Item = &'a str>,
+    ) -> Result<(), ValidationError> {
+        validate_name(&self.name, taken)?;
+
+        if self.proxies.is_empty() {
+            return Err(ValidationError::NoProxies);
+        }
+
+        for proxy in self.proxies.iter() {
+            proxy.validate()?;
+        }
+
+        for rule in self.rules.iter() {
+            rule.validate(self.proxies.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn configuration(value: serde_json::Value) -> Configuration {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn proxy(url: &str, port: u16, login: &str, password: &str) -> Proxy {
+        serde_json::from_value(json!({ "proxy_type": "socks5", "url": url, "port": port, "login": login, "password": password }))
+            .unwrap()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(validate_name("work", ["home", "office"].into_iter()), Ok(()));
+        assert_eq!(validate_name("", std::iter::empty()), Err(ValidationError::EmptyName));
+        assert_eq!(
+            validate_name("home", ["home"].into_iter()),
+            Err(ValidationError::DuplicateName("home".to_string()))
+        );
+        for invalid in [".", "..", "a/b", "../etc", "a\\b", "a\0b"] {
+            assert_eq!(
+                validate_name(invalid, std::iter::empty()),
+                Err(ValidationError::InvalidName(invalid.to_string()))
+            );
+        }
+        // dots are fine as long as the name stays in its directory
+        assert_eq!(validate_name("...", std::iter::empty()), Ok(()));
+        assert_eq!(validate_name("v1.2", std::iter::empty()), Ok(()));
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(validate_host("10.0.0.1"), Ok(()));
+        assert_eq!(validate_host("proxy.example.com"), Ok(()));
+        assert_eq!(validate_host(""), Err(ValidationError::EmptyProxyUrl));
+        for invalid in ["a b", "a;b", "a{", "a}", "a\tb"] {
+            assert_eq!(validate_host(invalid), Err(ValidationError::InvalidProxyUrl(invalid.to_string())));
+        }
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_port("1"), Ok(1));
+        assert_eq!(parse_port("65535"), Ok(65535));
+        for invalid in ["0", "65536", "-1", "", " 80", "http"] {
+            assert_eq!(parse_port(invalid), Err(ValidationError::InvalidPort(invalid.to_string())));
+        }
+    }
+
+    #[test]
+    fn proxies() {
+        assert_eq!(proxy("10.0.0.1", 1080, "", "").validate(), Ok(()));
+        assert_eq!(proxy("10.0.0.1", 1080, "user", "secret").validate(), Ok(()));
+        assert_eq!(proxy("", 1080, "", "").validate(), Err(ValidationError::EmptyProxyUrl));
+        assert_eq!(
+            proxy("10.0.0.1", 0, "", "").validate(),
+            Err(ValidationError::InvalidPort("0".to_string()))
+        );
+        assert_eq!(
+            proxy("10.0.0.1", 1080, "user", "").validate(),
+            Err(ValidationError::IncompleteCredentials)
+        );
+        assert_eq!(
+            proxy("10.0.0.1", 1080, "user", "se cret").validate(),
+            Err(ValidationError::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    fn rules_need_a_proxy_to_redirect_to() {
+        let rule = |to_port: u16, action: &str| -> IptablesRule {
+            serde_json::from_value(json!({ "dport": "80", "to_port": to_port, "action": action })).unwrap()
+        };
+
+        assert_eq!(rule(BASE_LOCAL_PORT, "REDIRECT").validate(1), Ok(()));
+        assert_eq!(rule(BASE_LOCAL_PORT + 1, "REDIRECT").validate(2), Ok(()));
+        assert_eq!(
+            rule(BASE_LOCAL_PORT + 1, "REDIRECT").validate(1),
+            Err(ValidationError::UnknownRuleTarget(BASE_LOCAL_PORT + 1))
+        );
+        // a return rule doesn't go to a proxy
+        assert_eq!(rule(0, "RETURN").validate(0), Ok(()));
+    }
+
+    #[test]
+    fn configurations() {
+        let valid = json!({
+            "version": 1,
+            "name": "office",
+            "proxies": [{ "proxy_type": "socks5", "url": "10.0.0.1", "port": 1080 }],
+            "rules": [{ "dport": "80", "to_port": BASE_LOCAL_PORT, "action": "REDIRECT" }]
+        });
+        assert_eq!(configuration(valid.clone()).validate(["home"].into_iter()), Ok(()));
+        assert_eq!(
+            configuration(valid.clone()).validate(["office"].into_iter()),
+            Err(ValidationError::DuplicateName("office".to_string()))
+        );
+
+        let mut without_proxies = valid.clone();
+        without_proxies["proxies"] = json!([]);
+        assert_eq!(configuration(without_proxies).validate(std::iter::empty()), Err(ValidationError::NoProxies));
+
+        // every proxy and rule is checked
+        let mut bad_proxy = valid.clone();
+        bad_proxy["proxies"][0]["url"] = json!("");
+        assert_eq!(configuration(bad_proxy).validate(std::iter::empty()), Err(ValidationError::EmptyProxyUrl));
+
+        let mut bad_rule = valid;
+        bad_rule["rules"][0]["to_port"] = json!(BASE_LOCAL_PORT + 1);
+        assert_eq!(
+            configuration(bad_rule).validate(std::iter::empty()),
+            Err(ValidationError::UnknownRuleTarget(BASE_LOCAL_PORT + 1))
+        );
+    }
+}